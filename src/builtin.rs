@@ -1,17 +1,232 @@
 use std::rc::Rc;
 use crate::H4;
-use crate::scopes::Value;
+use crate::scopes::{Builtin, Value};
+use crate::data::{Format, load_data};
+use crate::dump::{dump_text, dump_json};
+use crate::case;
+use crate::eval;
+use regex::Regex;
 use std::process::Command;
 use std::fs;
-use rquickjs;
+
+/// The builtins every `H4` starts with.
+pub fn builtins<'a>() -> Vec<Builtin<'a>> {
+    return vec![
+        Builtin::new("@define", 2, Some(2), "@define(name, body): Defines a macro, its arguments are @arg0, @arg1...", builtin_define),
+        Builtin::new("@undefine", 1, None, "@undefine(names...): Removes the innermost definition of every name", builtin_undefine),
+        Builtin::new("@undefineAll", 1, None, "@undefineAll(names...): Removes every definition of every name, in all scopes", builtin_undefine_all),
+        Builtin::new("@pushdef", 2, Some(2), "@pushdef(name, body): Redefines a macro until @popdef, without a new scope", builtin_pushdef),
+        Builtin::new("@popdef", 1, None, "@popdef(names...): Restores the definitions replaced by the last @pushdef of every name", builtin_popdef),
+        Builtin::new("@dump", 0, Some(1), "@dump([json]): Prints every scope to stderr", builtin_dump),
+        Builtin::new("@pushScope", 0, Some(0), "@pushScope: Starts a scope for new definitions", builtin_push_scope),
+        Builtin::new("@popScope", 0, Some(0), "@popScope: Drops the innermost scope and its definitions", builtin_pop_scope),
+        Builtin::new("@skip", 0, Some(1), "@skip([count]): Drops the next count chars of input, 1 by default", builtin_skip),
+        Builtin::new("@jsEval", 1, Some(1), "@jsEval(expression): Expands to the value of a JavaScript expression", builtin_jseval),
+        Builtin::new("@js", 1, Some(1), "@js(script): Runs JavaScript statements, expanding to what they emit()", builtin_js),
+        Builtin::new("@let", 2, Some(2), "@let(name, expression): Defines a variable in the current scope from JavaScript", builtin_let),
+        Builtin::new("@set", 2, Some(2), "@set(name, expression): Assigns JavaScript to the innermost variable called name", builtin_set),
+        Builtin::new("@get", 1, Some(1), "@get(expression): Same as @jsEval", builtin_get),
+        Builtin::new("@shell", 1, Some(1), "@shell(command): Expands to the output of a shell command", builtin_shell),
+        Builtin::new("@env", 1, Some(2), "@env(name[, default]): Expands to an environment variable", builtin_env),
+        Builtin::new("@import", 1, Some(1), "@import(path): Processes another file in place", builtin_import),
+        Builtin::new("@for", 3, Some(3), "@for(name, list, body): Expands body for every element of a JavaScript list", builtin_for),
+        Builtin::new("@loadJSON", 2, Some(2), "@loadJSON(name, path): Defines a variable from a JSON file", builtin_load_json),
+        Builtin::new("@loadTOML", 2, Some(2), "@loadTOML(name, path): Defines a variable from a TOML file", builtin_load_toml),
+        Builtin::new("@loadYAML", 2, Some(2), "@loadYAML(name, path): Defines a variable from a YAML file", builtin_load_yaml),
+        Builtin::new("@loadCSV", 2, Some(2), "@loadCSV(name, path): Defines a list of rows from a CSV file with a header", builtin_load_csv),
+        Builtin::new("@traceOn", 0, None, "@traceOn([names...]): Logs calls to the given macros, or to all of them", builtin_trace_on),
+        Builtin::new("@traceOff", 0, None, "@traceOff([names...]): Stops logging calls to the given macros, or to all of them", builtin_trace_off),
+        Builtin::new("@len", 1, Some(1), "@len(text): Expands to the number of characters of text", builtin_len),
+        Builtin::new("@substr", 2, Some(3), "@substr(text, start[, length]): Expands to part of text, counting characters from 0", builtin_substr),
+        Builtin::new("@replace", 3, Some(3), "@replace(text, from, to): Replaces every occurrence of from", builtin_replace),
+        Builtin::new("@upper", 1, Some(1), "@upper(text): Converts text to uppercase", builtin_upper),
+        Builtin::new("@lower", 1, Some(1), "@lower(text): Converts text to lowercase", builtin_lower),
+        Builtin::new("@trim", 1, Some(1), "@trim(text): Removes whitespace around text", builtin_trim),
+        Builtin::new("@split", 1, Some(2), "@split(text[, separator]): Expands to the quoted pieces of text separated by commas, splitting at whitespace by default", builtin_split),
+        Builtin::new("@snakeCase", 1, Some(1), "@snakeCase(text): Converts an identifier to snake_case", builtin_snake_case),
+        Builtin::new("@camelCase", 1, Some(1), "@camelCase(text): Converts an identifier to camelCase", builtin_camel_case),
+        Builtin::new("@pascalCase", 1, Some(1), "@pascalCase(text): Converts an identifier to PascalCase", builtin_pascal_case),
+        Builtin::new("@kebabCase", 1, Some(1), "@kebabCase(text): Converts an identifier to kebab-case", builtin_kebab_case),
+        Builtin::new("@regexReplace", 3, Some(3), "@regexReplace(text, pattern, replacement): Replaces every match, $1 or ${name} being a group", builtin_regex_replace),
+        Builtin::new("@regexMatch", 3, Some(4), "@regexMatch(text, pattern, then[, else]): Expands to then if pattern matches text, to else otherwise", builtin_regex_match),
+        Builtin::new("@regexCapture", 2, Some(3), "@regexCapture(text, pattern[, body]): Defines @capture0, @capture1... and @capture_name for named groups in a new scope, popped after body or by @popScope", builtin_regex_capture),
+        Builtin::new("@eval", 1, Some(3), "@eval(expression[, radix[, width]]): Expands to the value of an arithmetic expression, zero-padded to width digits. Quote expressions with parentheses, as a ) ends the call", builtin_eval),
+        Builtin::new("@help", 0, Some(1), "@help([name]): Expands to the help of a builtin, or to the list of builtins", builtin_help),
+    ]
+}
+
+fn parse_index<T: std::str::FromStr>(name: &str, what: &str, arg: &str) -> T {
+    return arg.trim().parse()
+        .unwrap_or_else(|_| panic!("{name}: The {what} must be a non-negative integer, got `{arg}'"))
+}
+
+pub fn builtin_len(_h4: &mut H4, args: &Vec<String>) -> String {
+    return args[0].chars().count().to_string()
+}
+
+pub fn builtin_substr(_h4: &mut H4, args: &Vec<String>) -> String {
+    let start = parse_index("@substr", "start", &args[1]);
+    let chars = args[0].chars().skip(start);
+    match args.get(2) {
+        Some(length) => return chars.take(parse_index("@substr", "length", length)).collect(),
+        None => return chars.collect(),
+    }
+}
+
+pub fn builtin_replace(_h4: &mut H4, args: &Vec<String>) -> String {
+    if args[1].is_empty() {
+        panic!("@replace: The text to replace cannot be empty")
+    }
+    return args[0].replace(&args[1], &args[2])
+}
+
+pub fn builtin_upper(_h4: &mut H4, args: &Vec<String>) -> String {
+    return args[0].to_uppercase()
+}
+
+pub fn builtin_lower(_h4: &mut H4, args: &Vec<String>) -> String {
+    return args[0].to_lowercase()
+}
+
+pub fn builtin_trim(_h4: &mut H4, args: &Vec<String>) -> String {
+    return args[0].trim().to_string()
+}
+
+pub fn builtin_split(_h4: &mut H4, args: &Vec<String>) -> String {
+    let pieces: Vec<&str> = match args.get(1).filter(|separator| !separator.is_empty()) {
+        Some(separator) => args[0].split(separator.as_str()).collect(),
+        None => args[0].split_whitespace().collect(),
+    };
+    return pieces.iter()
+        .map(|piece| format!("`{piece}'"))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+pub fn builtin_snake_case(_h4: &mut H4, args: &Vec<String>) -> String {
+    return case::snake_case(&args[0])
+}
+
+pub fn builtin_camel_case(_h4: &mut H4, args: &Vec<String>) -> String {
+    return case::camel_case(&args[0])
+}
+
+pub fn builtin_pascal_case(_h4: &mut H4, args: &Vec<String>) -> String {
+    return case::pascal_case(&args[0])
+}
+
+pub fn builtin_kebab_case(_h4: &mut H4, args: &Vec<String>) -> String {
+    return case::kebab_case(&args[0])
+}
+
+fn compile_regex(name: &str, pattern: &str) -> Regex {
+    return Regex::new(pattern).unwrap_or_else(|e| panic!("{name}: Invalid pattern `{pattern}': {e}"))
+}
+
+pub fn builtin_regex_replace(_h4: &mut H4, args: &Vec<String>) -> String {
+    let regex = compile_regex("@regexReplace", &args[1]);
+    return regex.replace_all(&args[0], args[2].as_str()).to_string()
+}
+
+pub fn builtin_regex_match(_h4: &mut H4, args: &Vec<String>) -> String {
+    let regex = compile_regex("@regexMatch", &args[1]);
+    if regex.is_match(&args[0]) {
+        return args[2].clone()
+    }
+    return args.get(3).cloned().unwrap_or_default()
+}
+
+pub fn builtin_regex_capture(h4: &mut H4, args: &Vec<String>) -> String {
+    let regex = compile_regex("@regexCapture", &args[1]);
+    let captures = regex.captures(&args[0])
+        .unwrap_or_else(|| panic!("@regexCapture: `{}' does not match `{}'", args[1], args[0]));
+    h4.scopes.push_scope();
+    for (i, group) in captures.iter().enumerate() {
+        let text = group.map(|group| group.as_str()).unwrap_or_default();
+        h4.scopes.let_variable(&format!("@capture{i}"), Value::Plain(text.to_string()));
+    }
+    for name in regex.capture_names().flatten() {
+        let text = captures.name(name).map(|group| group.as_str()).unwrap_or_default();
+        h4.scopes.let_variable(&format!("@capture_{name}"), Value::Plain(text.to_string()));
+    }
+    match args.get(2) {
+        Some(body) => return format!("{body}`'@popScope\n"),
+        None => {
+            h4.iter.next();
+            return String::new()
+        }
+    }
+}
+
+pub fn builtin_eval(_h4: &mut H4, args: &Vec<String>) -> String {
+    let radix = match args.get(1).filter(|radix| !radix.is_empty()) {
+        Some(radix) => parse_index("@eval", "radix", radix),
+        None => 10,
+    };
+    let width = match args.get(2).filter(|width| !width.is_empty()) {
+        Some(width) => parse_index("@eval", "width", width),
+        None => 0,
+    };
+    return eval::evaluate(&args[0])
+        .and_then(|number| eval::format(number, radix, width))
+        .unwrap_or_else(|e| panic!("@eval: {e} in `{}'", args[0]))
+}
+
+/// One line per registered builtin, with its help.
+pub fn list_builtins(h4: &H4) -> String {
+    return h4.builtins.iter()
+        .map(|builtin| format!("{}\n", builtin.help_line()))
+        .collect()
+}
+
+pub fn builtin_help(h4: &mut H4, args: &Vec<String>) -> String {
+    let text = match args.first().filter(|name| !name.is_empty()) {
+        None => list_builtins(h4),
+        Some(name) => {
+            let builtin = h4.builtin(name).unwrap_or_else(|| panic!("@help: Unknown builtin {name}"));
+            builtin.help_line()
+        }
+    };
+    return format!("`{text}'") // Quoted, or the macro names in it would expand
+}
 
 pub fn builtin_define(h4: &mut H4, args: &Vec<String>) -> String {
-    let scopes = Rc::clone(&mut h4.scopes);
+    let scopes = Rc::clone(&h4.scopes);
     scopes.let_variable(&args[0], Value::Plain(args[1].clone()));
     h4.iter.next();
     return String::new()
 }
 
+pub fn builtin_undefine(h4: &mut H4, args: &Vec<String>) -> String {
+    for name in args {
+        h4.scopes.remove_variable(name);
+    }
+    h4.iter.next();
+    return String::new()
+}
+
+pub fn builtin_undefine_all(h4: &mut H4, args: &Vec<String>) -> String {
+    for name in args {
+        h4.scopes.remove_all_variables(name);
+    }
+    h4.iter.next();
+    return String::new()
+}
+
+pub fn builtin_pushdef(h4: &mut H4, args: &Vec<String>) -> String {
+    h4.scopes.push_definition(&args[0], Value::Plain(args[1].clone()));
+    h4.iter.next();
+    return String::new()
+}
+
+pub fn builtin_popdef(h4: &mut H4, args: &Vec<String>) -> String {
+    for name in args {
+        h4.scopes.pop_definition(name);
+    }
+    h4.iter.next();
+    return String::new()
+}
+
 pub fn builtin_let(h4: &mut H4, args: &Vec<String>) -> String {
     h4.scopes.let_variable(&args[0], Value::JS(h4.eval_js(args[1].clone())));
     h4.iter.next();
@@ -19,10 +234,6 @@ pub fn builtin_let(h4: &mut H4, args: &Vec<String>) -> String {
 }
 
 pub fn builtin_for(h4: &mut H4, args: &Vec<String>) -> String {
-    if args.len() < 3 {
-        panic!("@for: Not enough arguments")
-    }
-
     h4.iter.next();
     let name = &args[0];
     let list = h4.scopes.get_variable(&args[1]);
@@ -97,7 +308,7 @@ pub fn builtin_pop_scope(h4: &mut H4, _args: &Vec<String>) -> String {
 
 pub fn builtin_skip(h4: &mut H4, args: &Vec<String>) -> String {
     let mut times = 1;
-    if let Some(arg) = args.get(0) {
+    if let Some(arg) = args.first() {
         times = arg.parse::<i32>().unwrap_or(0);
     }
     for _ in 0..times {
@@ -112,36 +323,82 @@ pub fn builtin_jseval(h4: &mut H4, args: &Vec<String>) -> String {
     return value
 }
 
+pub fn builtin_js(h4: &mut H4, args: &Vec<String>) -> String {
+    return h4.run_js(args[0].clone())
+}
+
+pub fn builtin_env(_h4: &mut H4, args: &Vec<String>) -> String {
+    match std::env::var(&args[0]) {
+        Ok(value) => return value,
+        Err(_) => return args.get(1).cloned().unwrap_or_default()
+    }
+}
+
 pub fn builtin_import(h4: &mut H4, args: &Vec<String>) -> String {
-    let value = match args.get(0) {
-        Some(file) => {
-            fs::read_to_string(file)
-                .unwrap_or_else(|e| panic!("@import: Could not read file {e}"))
-        }
-        None => panic!("@import: No file provided")
-    };
+    h4.dependencies.borrow_mut().push(args[0].clone());
+    let value = fs::read_to_string(&args[0])
+        .unwrap_or_else(|e| panic!("@import: Could not read file {e}"));
     h4.iter.next();
     return value
 }
 
-pub fn builtin_dump(h4: &mut H4, _args: &Vec<String>) -> String {
-    let scopes = Rc::clone(&h4.scopes.scopes);
-    let scopes = scopes.borrow();
-    for (i, stack) in scopes.iter().enumerate() {
-        println!("Stack {i}:");
-        for (key, value) in stack {
-            let value = Rc::clone(value);
-            let value = value.borrow().clone();
-            match value {
-                Value::Plain(str) => {
-                    println!("{key}: {}", str.clone());
-                },
-                Value::Builtin(_) => {
-                    println!("{key}: <Builtin>");
-                }
-                _ => unimplemented!()
-            }
-        }
+fn load_data_file(h4: &mut H4, args: &Vec<String>, format: Format) -> String {
+    h4.dependencies.borrow_mut().push(args[1].clone());
+    let value = load_data(*h4.ctx, format, &args[1]);
+    h4.scopes.let_variable(&args[0], Value::JS(value));
+    h4.iter.next();
+    return String::new()
+}
+
+pub fn builtin_load_json(h4: &mut H4, args: &Vec<String>) -> String {
+    return load_data_file(h4, args, Format::Json)
+}
+
+pub fn builtin_load_toml(h4: &mut H4, args: &Vec<String>) -> String {
+    return load_data_file(h4, args, Format::Toml)
+}
+
+pub fn builtin_load_yaml(h4: &mut H4, args: &Vec<String>) -> String {
+    return load_data_file(h4, args, Format::Yaml)
+}
+
+pub fn builtin_load_csv(h4: &mut H4, args: &Vec<String>) -> String {
+    return load_data_file(h4, args, Format::Csv)
+}
+
+/// Logs calls to the given macros, or to every macro without arguments.
+pub fn builtin_trace_on(h4: &mut H4, args: &Vec<String>) -> String {
+    match traced_names(args) {
+        Some(names) => names.iter().for_each(|name| h4.trace.enable(name)),
+        None => h4.trace.enable_all(),
+    }
+    h4.iter.next();
+    return String::new()
+}
+
+/// Stops logging calls to the given macros, or to every macro without arguments.
+pub fn builtin_trace_off(h4: &mut H4, args: &Vec<String>) -> String {
+    match traced_names(args) {
+        Some(names) => names.iter().for_each(|name| h4.trace.disable(name)),
+        None => h4.trace.disable_all(),
+    }
+    h4.iter.next();
+    return String::new()
+}
+
+fn traced_names(args: &Vec<String>) -> Option<&[String]> {
+    if args.iter().all(|arg| arg.is_empty()) {
+        return None
+    }
+    return Some(args)
+}
+
+/// Prints every scope to stderr, as JSON with `@dump(json)`.
+pub fn builtin_dump(h4: &mut H4, args: &Vec<String>) -> String {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("json") => eprintln!("{}", dump_json(h4)),
+        Some("") | None => eprint!("{}", dump_text(h4)),
+        Some(format) => panic!("@dump: Unknown format `{format}', expected `json'"),
     }
     h4.iter.next();
     return String::new()
@@ -155,7 +412,7 @@ pub fn run_shell(command: String) -> String {
     return output
         .map(|o| {
             let mut stdout = String::from_utf8_lossy(&o.stdout).to_string();
-            if stdout.chars().last().unwrap() == '\n' {
+            if stdout.ends_with('\n') {
                 stdout.pop();
             }
             return stdout
@@ -163,9 +420,10 @@ pub fn run_shell(command: String) -> String {
         .unwrap_or_else(|e| panic!("@shell: Program exited with error: {e}"));
 }
 
-pub fn builtin_shell(_h4: &mut H4, args: &Vec<String>) -> String {
-    match args.get(0) {
-        Some(command) => return run_shell(command.clone()),
-        None => panic!("@shell: Command not found'")
+pub fn builtin_shell(h4: &mut H4, args: &Vec<String>) -> String {
+    if h4.sandboxed.get() {
+        panic!("@shell: Not allowed in sandbox mode")
     }
+    h4.shell_used.set(true);
+    return run_shell(args[0].clone())
 }
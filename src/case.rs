@@ -0,0 +1,79 @@
+/// Splits an identifier into lowercase words, at separators and at case changes,
+/// so `parseHTTPResponse`, `parse_http_response` and `Parse-HTTP-Response` give the same words.
+pub fn words(text: &str) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for (i, &chr) in chars.iter().enumerate() {
+        if !chr.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue
+        }
+        if chr.is_uppercase() && !word.is_empty() {
+            let previous = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+            // Either `aB`, or the last capital of an acronym followed by a word, as in `HTTPResponse`
+            if !previous.is_uppercase() || next_is_lower {
+                words.push(std::mem::take(&mut word));
+            }
+        }
+        word.extend(chr.to_lowercase());
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    return words
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => return first.to_uppercase().chain(chars).collect(),
+        None => return String::new(),
+    }
+}
+
+pub fn snake_case(text: &str) -> String {
+    return words(text).join("_")
+}
+
+pub fn kebab_case(text: &str) -> String {
+    return words(text).join("-")
+}
+
+pub fn pascal_case(text: &str) -> String {
+    return words(text).iter().map(|word| capitalize(word)).collect()
+}
+
+pub fn camel_case(text: &str) -> String {
+    let words = words(text);
+    let mut result = words.first().cloned().unwrap_or_default();
+    for word in words.iter().skip(1) {
+        result.push_str(&capitalize(word));
+    }
+    return result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_words() {
+        assert_eq!(words("parseHTTPResponse"), vec!["parse", "http", "response"]);
+        assert_eq!(words("  Parse-HTTP_response2 "), vec!["parse", "http", "response2"]);
+        assert_eq!(words("vec3"), vec!["vec3"]);
+        assert!(words("--").is_empty());
+    }
+
+    #[test]
+    fn test_cases() {
+        assert_eq!(snake_case("vecAdd"), "vec_add");
+        assert_eq!(kebab_case("VecAdd"), "vec-add");
+        assert_eq!(pascal_case("vec_add"), "VecAdd");
+        assert_eq!(camel_case("Vec add"), "vecAdd");
+        assert_eq!(camel_case("ÉCOLE_NORMALE"), "écoleNormale");
+    }
+}
@@ -0,0 +1,155 @@
+use rquickjs::Ctx;
+use std::fs;
+
+pub fn json_to_js<'js>(ctx: Ctx<'js>, value: &serde_json::Value) -> rquickjs::Value<'js> {
+    match value {
+        serde_json::Value::Null => return rquickjs::Value::new_null(ctx),
+        serde_json::Value::Bool(bool) => return rquickjs::Value::new_bool(ctx, *bool),
+        serde_json::Value::Number(number) => {
+            return rquickjs::Value::new_number(ctx, number.as_f64().unwrap_or(f64::NAN))
+        }
+        serde_json::Value::String(str) => {
+            return rquickjs::String::from_str(ctx, str)
+                .expect("Could not create string")
+                .into_value()
+        }
+        serde_json::Value::Array(elements) => {
+            let array = rquickjs::Array::new(ctx).expect("Could not create array");
+            for (i, element) in elements.iter().enumerate() {
+                array.set(i, json_to_js(ctx, element)).expect("Could not set array element");
+            }
+            return array.into_value()
+        }
+        serde_json::Value::Object(entries) => {
+            let object = rquickjs::Object::new(ctx).expect("Could not create object");
+            for (key, entry) in entries {
+                object.set(key.as_str(), json_to_js(ctx, entry)).expect("Could not set object key");
+            }
+            return object.into_value()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+    Csv,
+}
+
+impl Format {
+    /// Guesses the format from the file extension, defaulting to JSON.
+    pub fn from_path(path: &str) -> Format {
+        let extension = std::path::Path::new(path)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("toml") => Format::Toml,
+            Some("yaml") | Some("yml") => Format::Yaml,
+            Some("csv") => Format::Csv,
+            _ => Format::Json,
+        }
+    }
+
+    pub fn builtin_name(&self) -> &'static str {
+        match self {
+            Format::Json => "@loadJSON",
+            Format::Toml => "@loadTOML",
+            Format::Yaml => "@loadYAML",
+            Format::Csv => "@loadCSV",
+        }
+    }
+}
+
+fn toml_to_json(value: toml::Value) -> serde_json::Value {
+    match value {
+        toml::Value::String(str) => return serde_json::Value::String(str),
+        toml::Value::Integer(int) => return serde_json::Value::from(int),
+        toml::Value::Float(float) => return serde_json::Value::from(float),
+        toml::Value::Boolean(bool) => return serde_json::Value::Bool(bool),
+        toml::Value::Datetime(datetime) => return serde_json::Value::String(datetime.to_string()),
+        toml::Value::Array(elements) => {
+            return serde_json::Value::Array(elements.into_iter().map(toml_to_json).collect())
+        }
+        toml::Value::Table(entries) => {
+            return serde_json::Value::Object(
+                entries.into_iter().map(|(key, entry)| (key, toml_to_json(entry))).collect()
+            )
+        }
+    }
+}
+
+fn parse_csv(text: &str) -> Result<serde_json::Value, csv::Error> {
+    let mut reader = csv::Reader::from_reader(text.as_bytes());
+    let headers = reader.headers()?.clone();
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let row: serde_json::Map<String, serde_json::Value> = headers.iter()
+            .zip(record.iter())
+            .map(|(header, field)| (header.to_string(), serde_json::Value::from(field)))
+            .collect();
+        rows.push(serde_json::Value::Object(row));
+    }
+    return Ok(serde_json::Value::Array(rows))
+}
+
+/// Splits a `--data` value into a variable name and a path,
+/// the name defaulting to the file name without its extension.
+pub fn data_definition(data: &str) -> (String, String) {
+    if let Some((name, path)) = data.split_once('=') {
+        return (name.to_string(), path.to_string())
+    }
+    let name = std::path::Path::new(data)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| panic!("--data: Cannot name {data}, use NAME=PATH"));
+    return (name, data.to_string())
+}
+
+/// Parses a data file into a QuickJS value, panicking with the file and line on errors.
+pub fn load_data<'js>(ctx: Ctx<'js>, format: Format, path: &str) -> rquickjs::Value<'js> {
+    let loader = format.builtin_name();
+    let text = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("{loader}: Could not read {path}: {e}"));
+    let value = match format {
+        Format::Json => serde_json::from_str(&text).map_err(|e| e.to_string()),
+        Format::Toml => toml::from_str(&text).map(toml_to_json).map_err(|e| e.to_string()),
+        Format::Yaml => serde_yaml::from_str(&text).map_err(|e| e.to_string()),
+        Format::Csv => parse_csv(&text).map_err(|e| e.to_string()),
+    };
+    let value = value.unwrap_or_else(|e| panic!("{loader}: Could not parse {path}: {e}"));
+    return json_to_js(ctx, &value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_csv() {
+        let rows = parse_csv("name,size\nvec,3\nmat,\"4,4\"\n").unwrap();
+        assert_eq!(rows, json!([{"name": "vec", "size": "3"}, {"name": "mat", "size": "4,4"}]));
+        let error = parse_csv("name,size\nvec,3\nmat\n").unwrap_err().to_string();
+        assert!(error.contains("line: 3"), "{error}");
+    }
+
+    #[test]
+    fn test_toml_to_json() {
+        let text = "title = \"vec\"\ncreated = 2024-05-01T10:00:00Z\n[size]\nrows = 3\nscale = 0.5\nflags = [true, false]\n";
+        let value = toml_to_json(toml::from_str(text).unwrap());
+        assert_eq!(value, json!({
+            "title": "vec",
+            "created": "2024-05-01T10:00:00Z",
+            "size": {"rows": 3, "scale": 0.5, "flags": [true, false]},
+        }));
+    }
+
+    #[test]
+    fn test_data_definition() {
+        assert_eq!(data_definition("spec=data/vec.json"), ("spec".to_string(), "data/vec.json".to_string()));
+        assert_eq!(data_definition("data/vec.yaml"), ("vec".to_string(), "data/vec.yaml".to_string()));
+    }
+}
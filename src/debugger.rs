@@ -0,0 +1,181 @@
+use crate::H4;
+use crate::dump::dump_text;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+const HELP: &str = "Commands:
+  s, step          Evaluate this macro and stop at the next one
+  c, continue      Run until a breakpoint
+  b, break NAME    Stop before every call to NAME
+  d, delete NAME   Remove the breakpoint on NAME
+  i, input         Show the pending input
+  scopes           Show every scope
+  o, output        Show where the output is going
+  e, eval JS       Evaluate a JavaScript expression
+  q, quit          Stop processing
+  h, help          Show this message";
+
+/// How much pending input is shown at every stop.
+const PREVIEW_LENGTH: usize = 200;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Step,
+    Continue,
+    Break(String),
+    Delete(String),
+    Input,
+    Scopes,
+    Output,
+    Eval(String),
+    Quit,
+    Help,
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Command, String> {
+        let line = line.trim();
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let argument = argument.trim().to_string();
+        let command = match command {
+            "" | "s" | "step" => Command::Step,
+            "c" | "continue" => Command::Continue,
+            "b" | "break" => Command::Break(argument.clone()),
+            "d" | "delete" => Command::Delete(argument.clone()),
+            "i" | "input" => Command::Input,
+            "scopes" => Command::Scopes,
+            "o" | "output" => Command::Output,
+            "e" | "eval" => Command::Eval(argument.clone()),
+            "q" | "quit" => Command::Quit,
+            "h" | "help" => Command::Help,
+            command => return Err(format!("Unknown command {command}, try help")),
+        };
+        let needs_argument = matches!(command, Command::Break(_) | Command::Delete(_) | Command::Eval(_));
+        if needs_argument && argument.is_empty() {
+            return Err(format!("{} needs an argument, try help", line))
+        }
+        return Ok(command)
+    }
+}
+
+/// Interactive prompt that stops before macro evaluations.
+pub struct Debugger {
+    stepping: bool,
+    breakpoints: HashSet<String>,
+    input: Box<dyn BufRead>,
+}
+
+impl Debugger {
+    /// Reads commands from the terminal, so stdin can still be the processed input.
+    pub fn new() -> io::Result<Debugger> {
+        let tty = File::open("/dev/tty")?;
+        return Ok(Debugger::with_input(Box::new(BufReader::new(tty))))
+    }
+
+    /// Reads commands from stdin, which must not be the processed input too.
+    pub fn from_stdin() -> Debugger {
+        return Debugger::with_input(Box::new(BufReader::new(io::stdin())))
+    }
+
+    pub fn with_input(input: Box<dyn BufRead>) -> Debugger {
+        return Debugger { stepping: true, breakpoints: HashSet::new(), input }
+    }
+
+    pub fn should_stop(&self, name: &str) -> bool {
+        return self.stepping || self.breakpoints.contains(name)
+    }
+
+    fn read_command(&mut self) -> Option<String> {
+        eprint!("(h4db) ");
+        io::stderr().flush().ok();
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => return Some(line),
+        }
+    }
+
+    /// Shows the call about to be evaluated and handles commands until told to go on.
+    pub fn stop(&mut self, h4: &mut H4, name: &str, args: &Vec<String>, location: (usize, usize)) {
+        eprintln!("{}:{}:{}: {name}({})", h4.file_name, location.0, location.1, args.join(", "));
+        show_input(h4);
+        loop {
+            let line = match self.read_command() {
+                Some(line) => line,
+                None => {
+                    self.stepping = false; // Nothing more to read, runs to the end
+                    self.breakpoints.clear();
+                    return
+                }
+            };
+            let command = match Command::parse(&line) {
+                Ok(command) => command,
+                Err(message) => {
+                    eprintln!("{message}");
+                    continue
+                }
+            };
+            match command {
+                Command::Step => {
+                    self.stepping = true;
+                    return
+                }
+                Command::Continue => {
+                    self.stepping = false;
+                    return
+                }
+                Command::Break(name) => {
+                    eprintln!("Breakpoint on {name}");
+                    self.breakpoints.insert(name);
+                }
+                Command::Delete(name) => {
+                    if !self.breakpoints.remove(&name) {
+                        eprintln!("No breakpoint on {name}");
+                    }
+                }
+                Command::Input => show_input(h4),
+                Command::Scopes => eprint!("{}", dump_text(h4)),
+                Command::Output => show_output(h4),
+                Command::Eval(js) => {
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        let value = h4.eval_js(js);
+                        return h4.js_value_to_string(value)
+                    }));
+                    if let Ok(result) = result {
+                        eprintln!("{result}");
+                    }
+                }
+                Command::Quit => panic!("Stopped from the debugger"),
+                Command::Help => eprintln!("{HELP}"),
+            }
+        }
+    }
+}
+
+fn show_input(h4: &mut H4) {
+    let preview = h4.iter.preview(PREVIEW_LENGTH);
+    eprintln!("Pending input: `{}'", preview.replace('\n', "\\n"));
+}
+
+fn show_output(h4: &H4) {
+    match h4.captures.len() {
+        0 => eprintln!("Output: {}", h4.sink.describe()),
+        depth => eprintln!("Output: collecting arguments of {depth} nested calls, then {}", h4.sink.describe()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Command::parse("\n"), Ok(Command::Step));
+        assert_eq!(Command::parse("b  pair\n"), Ok(Command::Break("pair".to_string())));
+        assert_eq!(Command::parse("eval 1 + 2"), Ok(Command::Eval("1 + 2".to_string())));
+        assert!(Command::parse("break").is_err());
+        assert!(Command::parse("jump").is_err());
+    }
+}
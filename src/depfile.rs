@@ -0,0 +1,52 @@
+/// Escapes the characters Make gives a meaning to in rule paths.
+/// Backslashes stay as they are, Make does not unescape a doubled one.
+fn escape(path: &str) -> String {
+    return path.replace('$', "$$")
+        .replace(' ', "\\ ")
+        .replace('#', "\\#")
+        .replace(':', "\\:")
+}
+
+/// Builds a Make rule stating that `target` depends on every file in `dependencies`.
+/// If `force` is set, the target also depends on a phony rule, so it is always rebuilt.
+pub fn make_rule(target: &str, dependencies: &[String], force: bool) -> String {
+    let mut seen: Vec<&String> = Vec::new();
+    for dependency in dependencies {
+        if !seen.contains(&dependency) {
+            seen.push(dependency);
+        }
+    }
+
+    let mut rule = format!("{}:", escape(target));
+    for dependency in seen {
+        rule.push_str(" \\\n  ");
+        rule.push_str(&escape(dependency));
+    }
+    if force {
+        rule.push_str(" \\\n  FORCE\n\nFORCE:");
+    }
+    rule.push('\n');
+    return rule
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule() {
+        let dependencies = vec!["vec.h4".to_string(), "lib/sum.h4".to_string(), "vec.h4".to_string()];
+        assert_eq!(make_rule("vec.c", &dependencies, false), "vec.c: \\\n  vec.h4 \\\n  lib/sum.h4\n");
+    }
+
+    #[test]
+    fn test_escape_and_force() {
+        let dependencies = vec!["my spec.json".to_string()];
+        assert_eq!(
+            make_rule("out.c", &dependencies, true),
+            "out.c: \\\n  my\\ spec.json \\\n  FORCE\n\nFORCE:\n"
+        );
+        let dependencies = vec!["C:\\data\\$x#1.json".to_string()];
+        assert_eq!(make_rule("out.c", &dependencies, false), "out.c: \\\n  C\\:\\data\\$$x\\#1.json\n");
+    }
+}
@@ -0,0 +1,128 @@
+use crate::H4;
+use crate::scopes::{Scope, Value};
+use serde_json::json;
+use std::rc::Rc;
+
+/// Index of the nearest scope before `index` that also defines `name`.
+fn shadows(scopes: &[Scope], index: usize, name: &str) -> Option<usize> {
+    return (0..index).rev().find(|&i| scopes[i].contains_key(name))
+}
+
+/// Index of the nearest scope after `index` that also defines `name`.
+fn shadowed_by(scopes: &[Scope], index: usize, name: &str) -> Option<usize> {
+    return (index + 1..scopes.len()).find(|&i| scopes[i].contains_key(name))
+}
+
+fn sorted_names(scope: &Scope) -> Vec<String> {
+    let mut names: Vec<String> = scope.keys().cloned().collect();
+    names.sort();
+    return names
+}
+
+fn js_to_json<'a>(h4: &H4<'a, '_>, value: &rquickjs::Value<'a>) -> Option<String> {
+    return h4.ctx.json_stringify(value.clone()).ok()
+        .flatten()
+        .and_then(|str| str.to_string().ok())
+}
+
+fn describe<'a>(h4: &H4<'a, '_>, value: &Value<'a>) -> String {
+    match value {
+        Value::Plain(str) => return format!("`{str}'"),
+        Value::Builtin(_) => return "<Builtin>".to_string(),
+        Value::JS(value) if value.is_function() => {
+            return format!("<JS function> {}", h4.js_value_to_string(value.clone()))
+        }
+        Value::JS(value) => {
+            let text = js_to_json(h4, value).unwrap_or_else(|| h4.js_value_to_string(value.clone()));
+            return format!("<JS> {text}")
+        }
+    }
+}
+
+fn describe_json<'a>(h4: &H4<'a, '_>, value: &Value<'a>) -> serde_json::Value {
+    match value {
+        Value::Plain(str) => return json!({"type": "plain", "value": str}),
+        Value::Builtin(_) => return json!({"type": "builtin"}),
+        Value::JS(value) if value.is_function() => {
+            return json!({"type": "function", "source": h4.js_value_to_string(value.clone())})
+        }
+        Value::JS(value) => {
+            let value = js_to_json(h4, value)
+                .and_then(|json| serde_json::from_str(&json).ok())
+                .unwrap_or(serde_json::Value::Null);
+            return json!({"type": "js", "value": value})
+        }
+    }
+}
+
+/// Lists every scope and its definitions, noting which ones shadow each other.
+pub fn dump_text(h4: &H4) -> String {
+    let scopes = Rc::clone(&h4.scopes.scopes);
+    let scopes = scopes.borrow();
+    let mut text = String::new();
+    for (i, scope) in scopes.iter().enumerate() {
+        text.push_str(&format!("Scope {i}:\n"));
+        for name in sorted_names(scope) {
+            let value = scope[&name].borrow();
+            text.push_str(&format!("  {name}: {}", describe(h4, &value)));
+            if let Some(j) = shadows(&scopes, i, &name) {
+                text.push_str(&format!(" (shadows scope {j})"));
+            }
+            if let Some(j) = shadowed_by(&scopes, i, &name) {
+                text.push_str(&format!(" (shadowed by scope {j})"));
+            }
+            text.push('\n');
+        }
+    }
+    return text
+}
+
+/// Same as `dump_text`, as a JSON array with an object of definitions per scope.
+pub fn dump_json(h4: &H4) -> serde_json::Value {
+    let scopes = Rc::clone(&h4.scopes.scopes);
+    let scopes = scopes.borrow();
+    let mut dumped = Vec::new();
+    for (i, scope) in scopes.iter().enumerate() {
+        let mut definitions = serde_json::Map::new();
+        for name in sorted_names(scope) {
+            let mut definition = describe_json(h4, &scope[&name].borrow());
+            if let Some(j) = shadows(&scopes, i, &name) {
+                definition["shadows"] = json!(j);
+            }
+            if let Some(j) = shadowed_by(&scopes, i, &name) {
+                definition["shadowedBy"] = json!(j);
+            }
+            definitions.insert(name, definition);
+        }
+        dumped.push(serde_json::Value::Object(definitions));
+    }
+    return serde_json::Value::Array(dumped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::insertable::InsertableIterator;
+    use crate::sink::BufferSink;
+    use rquickjs::{Runtime, Context};
+
+    #[test]
+    fn test_dump_json() {
+        let input = "@define(`a', 1)\n@pushScope()\n@define(`a', 2)\n@let(`o', `{x: [1, 2]}')\n";
+        let runtime = Runtime::new().unwrap();
+        let context = Context::full(&runtime).unwrap();
+        context.with(|ctx| {
+            let boxed: Box<dyn Iterator<Item = char>> = Box::new(input.chars());
+            let mut h4 = H4::new(InsertableIterator::from(boxed), ctx);
+            h4.sink = Box::new(BufferSink::new());
+            h4.consume();
+            let dumped = dump_json(&h4);
+            h4.scopes.clear();
+
+            assert_eq!(dumped[0]["a"], json!({"type": "plain", "value": "1", "shadowedBy": 1}));
+            assert_eq!(dumped[1]["a"], json!({"type": "plain", "value": "2", "shadows": 0}));
+            assert_eq!(dumped[1]["o"], json!({"type": "js", "value": {"x": [1, 2]}}));
+            assert_eq!(dumped[0]["@dump"], json!({"type": "builtin"}));
+        });
+    }
+}
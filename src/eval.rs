@@ -0,0 +1,379 @@
+//! Arithmetic for `@eval`, with C operators and precedence plus `**`.
+//! Integers stay exact, any float operand makes the result a float.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    fn as_float(self) -> f64 {
+        match self {
+            Number::Int(int) => return int as f64,
+            Number::Float(float) => return float,
+        }
+    }
+
+    fn is_true(self) -> bool {
+        return self.as_float() != 0.0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(Number),
+    Operator(&'static str),
+    Open,
+    Close,
+}
+
+enum Expr {
+    Number(Number),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+/// Longest first, so `<=` is not read as `<`.
+const OPERATORS: [&str; 23] = [
+    "**", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+    "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~", "(", ")",
+];
+
+fn precedence(operator: &str) -> Option<u8> {
+    match operator {
+        "||" => return Some(1),
+        "&&" => return Some(2),
+        "|" => return Some(3),
+        "^" => return Some(4),
+        "&" => return Some(5),
+        "==" | "!=" => return Some(6),
+        "<" | "<=" | ">" | ">=" => return Some(7),
+        "<<" | ">>" => return Some(8),
+        "+" | "-" => return Some(9),
+        "*" | "/" | "%" => return Some(10),
+        _ => return None,
+    }
+}
+
+fn parse_number(text: &str) -> Result<Number, String> {
+    let digits = text.replace('_', "");
+    let radix = match digits.get(..2) {
+        Some("0x") | Some("0X") => Some(16),
+        Some("0b") | Some("0B") => Some(2),
+        Some("0o") | Some("0O") => Some(8),
+        _ => None,
+    };
+    let parsed = match radix {
+        Some(radix) => i64::from_str_radix(&digits[2..], radix).ok().map(Number::Int),
+        None if digits.contains(['.', 'e', 'E']) => digits.parse().ok().map(Number::Float),
+        None => digits.parse().ok().map(Number::Int),
+    };
+    return parsed.ok_or_else(|| format!("Invalid number {text}"))
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let chr = chars[i];
+        if chr.is_whitespace() {
+            i += 1;
+            continue
+        }
+        if chr.is_ascii_digit() || (chr == '.' && chars.get(i + 1).is_some_and(|next| next.is_ascii_digit())) {
+            let start = i;
+            while i < chars.len() {
+                let exponent_sign = (chars[i] == '+' || chars[i] == '-')
+                    && matches!(chars[i - 1], 'e' | 'E')
+                    && !chars[start..i].iter().any(|chr| matches!(chr, 'x' | 'X'));
+                if !(chars[i].is_ascii_alphanumeric() || chars[i] == '.' || chars[i] == '_' || exponent_sign) {
+                    break
+                }
+                i += 1;
+            }
+            tokens.push(Token::Number(parse_number(&chars[start..i].iter().collect::<String>())?));
+            continue
+        }
+        let rest: String = chars[i..].iter().take(2).collect();
+        let operator = OPERATORS.iter()
+            .find(|operator| rest.starts_with(**operator))
+            .ok_or_else(|| format!("Unexpected character {chr}"))?;
+        tokens.push(match *operator {
+            "(" => Token::Open,
+            ")" => Token::Close,
+            operator => Token::Operator(operator),
+        });
+        i += operator.len();
+    }
+    return Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        return self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        return token
+    }
+
+    /// Precedence climbing over the binary operators binding at least as tight as `minimum`.
+    fn binary(&mut self, minimum: u8) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        while let Some(Token::Operator(operator)) = self.peek() {
+            let operator = *operator;
+            let precedence = match precedence(operator) {
+                Some(precedence) if precedence >= minimum => precedence,
+                _ => break,
+            };
+            self.next();
+            let right = self.binary(precedence + 1)?;
+            left = Expr::Binary(operator, Box::new(left), Box::new(right));
+        }
+        return Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if let Some(Token::Operator(operator @ ("-" | "+" | "!" | "~"))) = self.peek() {
+            let operator = *operator;
+            self.next();
+            return Ok(Expr::Unary(operator, Box::new(self.unary()?)))
+        }
+        return self.power()
+    }
+
+    /// `**` binds tighter than unary operators on its left and is right associative.
+    fn power(&mut self) -> Result<Expr, String> {
+        let base = self.primary()?;
+        if self.peek() == Some(&Token::Operator("**")) {
+            self.next();
+            return Ok(Expr::Binary("**", Box::new(base), Box::new(self.unary()?)))
+        }
+        return Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(number)) => return Ok(Expr::Number(number)),
+            Some(Token::Open) => {
+                let inner = self.binary(0)?;
+                if self.next() != Some(Token::Close) {
+                    return Err("Missing )".to_string())
+                }
+                return Ok(inner)
+            }
+            Some(Token::Close) => return Err("Unexpected )".to_string()),
+            Some(Token::Operator(operator)) => return Err(format!("Unexpected {operator}")),
+            None => return Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+fn integers(operator: &str, a: Number, b: Number) -> Result<(i64, i64), String> {
+    match (a, b) {
+        (Number::Int(a), Number::Int(b)) => return Ok((a, b)),
+        _ => return Err(format!("{operator} needs integers")),
+    }
+}
+
+fn checked(result: Option<i64>) -> Result<Number, String> {
+    return result.map(Number::Int).ok_or_else(|| "Integer overflow".to_string())
+}
+
+fn unary(operator: &str, value: Number) -> Result<Number, String> {
+    match (operator, value) {
+        ("+", value) => return Ok(value),
+        ("-", Number::Int(int)) => return checked(int.checked_neg()),
+        ("-", Number::Float(float)) => return Ok(Number::Float(-float)),
+        ("!", value) => return Ok(Number::Int(!value.is_true() as i64)),
+        ("~", Number::Int(int)) => return Ok(Number::Int(!int)),
+        (operator, _) => return Err(format!("{operator} needs an integer")),
+    }
+}
+
+fn binary(operator: &str, a: Number, b: Number) -> Result<Number, String> {
+    let both_ints = matches!((a, b), (Number::Int(_), Number::Int(_)));
+    match operator {
+        "==" | "!=" | "<" | "<=" | ">" | ">=" => {
+            let ordering = match (a, b) {
+                (Number::Int(a), Number::Int(b)) => Some(a.cmp(&b)),
+                _ => a.as_float().partial_cmp(&b.as_float()),
+            };
+            let result = match operator {
+                "==" => ordering.is_some_and(|ordering| ordering.is_eq()),
+                "!=" => !ordering.is_some_and(|ordering| ordering.is_eq()),
+                "<" => ordering.is_some_and(|ordering| ordering.is_lt()),
+                "<=" => ordering.is_some_and(|ordering| ordering.is_le()),
+                ">" => ordering.is_some_and(|ordering| ordering.is_gt()),
+                _ => ordering.is_some_and(|ordering| ordering.is_ge()),
+            };
+            return Ok(Number::Int(result as i64))
+        }
+        "&" | "|" | "^" | "<<" | ">>" => {
+            let (a, b) = integers(operator, a, b)?;
+            let shift = u32::try_from(b).ok().filter(|shift| *shift < 64);
+            match operator {
+                "&" => return Ok(Number::Int(a & b)),
+                "|" => return Ok(Number::Int(a | b)),
+                "^" => return Ok(Number::Int(a ^ b)),
+                "<<" => {
+                    let shift = shift.ok_or_else(|| format!("Invalid shift {b}"))?;
+                    // Bits shifted out, or into the sign, do not come back
+                    return checked(a.checked_shl(shift).filter(|result| result >> shift == a))
+                }
+                _ => return shift.map(|shift| Number::Int(a >> shift)).ok_or_else(|| format!("Invalid shift {b}")),
+            }
+        }
+        _ if both_ints => {
+            let (a, b) = integers(operator, a, b)?;
+            if (operator == "/" || operator == "%") && b == 0 {
+                return Err("Division by zero".to_string())
+            }
+            match operator {
+                "+" => return checked(a.checked_add(b)),
+                "-" => return checked(a.checked_sub(b)),
+                "*" => return checked(a.checked_mul(b)),
+                "/" => return checked(a.checked_div(b)),
+                "%" => return checked(a.checked_rem(b)),
+                _ => {
+                    let exponent = u32::try_from(b).map_err(|_| format!("Invalid exponent {b}"))?;
+                    return checked(a.checked_pow(exponent))
+                }
+            }
+        }
+        _ => {
+            let (a, b) = (a.as_float(), b.as_float());
+            match operator {
+                "+" => return Ok(Number::Float(a + b)),
+                "-" => return Ok(Number::Float(a - b)),
+                "*" => return Ok(Number::Float(a * b)),
+                "/" => return Ok(Number::Float(a / b)),
+                "%" => return Ok(Number::Float(a % b)),
+                _ => return Ok(Number::Float(a.powf(b))),
+            }
+        }
+    }
+}
+
+fn evaluate_expr(expr: &Expr) -> Result<Number, String> {
+    match expr {
+        Expr::Number(number) => return Ok(*number),
+        Expr::Unary(operator, value) => return unary(operator, evaluate_expr(value)?),
+        Expr::Binary("&&", a, b) => {
+            let result = evaluate_expr(a)?.is_true() && evaluate_expr(b)?.is_true();
+            return Ok(Number::Int(result as i64))
+        }
+        Expr::Binary("||", a, b) => {
+            let result = evaluate_expr(a)?.is_true() || evaluate_expr(b)?.is_true();
+            return Ok(Number::Int(result as i64))
+        }
+        Expr::Binary(operator, a, b) => return binary(operator, evaluate_expr(a)?, evaluate_expr(b)?),
+    }
+}
+
+pub fn evaluate(expression: &str) -> Result<Number, String> {
+    let mut parser = Parser { tokens: tokenize(expression)?, position: 0 };
+    let expr = parser.binary(0)?;
+    if let Some(token) = parser.peek() {
+        return Err(format!("Unexpected {token:?} after the expression"))
+    }
+    return evaluate_expr(&expr)
+}
+
+/// Widest padding `format` accepts, far more than any number needs.
+pub const MAX_WIDTH: usize = 1024;
+
+/// Writes `number` in `radix`, zero-padded to at least `width` digits after the sign.
+pub fn format(number: Number, radix: u32, width: usize) -> Result<String, String> {
+    if !(2..=36).contains(&radix) {
+        return Err(format!("Invalid radix {radix}"))
+    }
+    if width > MAX_WIDTH {
+        return Err(format!("Invalid width {width}, at most {MAX_WIDTH}"))
+    }
+    let (negative, mut digits) = match number {
+        Number::Int(int) => {
+            let mut value = int.unsigned_abs();
+            let mut digits = Vec::new();
+            loop {
+                digits.push(std::char::from_digit((value % radix as u64) as u32, radix).unwrap());
+                value /= radix as u64;
+                if value == 0 {
+                    break
+                }
+            }
+            (int < 0, digits.iter().rev().collect::<String>())
+        }
+        Number::Float(_) if radix != 10 => return Err("Floats can only be written in radix 10".to_string()),
+        Number::Float(float) => (float.is_sign_negative() && float != 0.0, float.abs().to_string()),
+    };
+    if digits.len() < width {
+        digits.insert_str(0, &"0".repeat(width - digits.len()));
+    }
+    return Ok(if negative { format!("-{digits}") } else { digits })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> String {
+        return format(evaluate(expression).unwrap(), 10, 0).unwrap()
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), "7");
+        assert_eq!(eval("(1 + 2) * 3"), "9");
+        assert_eq!(eval("2 ** 3 ** 2"), "512");
+        assert_eq!(eval("-2 ** 2"), "-4");
+        assert_eq!(eval("1 | 2 ^ 3 & 4 << 1"), "3");
+        assert_eq!(eval("7 / 2 - 7 % 2"), "2");
+    }
+
+    #[test]
+    fn test_comparison_and_logic() {
+        assert_eq!(eval("1 < 2 == 1"), "1");
+        assert_eq!(eval("!0 && 3 >= 3"), "1");
+        assert_eq!(eval("0 && 1 / 0"), "0");
+        assert_eq!(eval("1 || 1 / 0"), "1");
+    }
+
+    #[test]
+    fn test_floats() {
+        assert_eq!(eval("7 / 2.0"), "3.5");
+        assert_eq!(eval("1e3 + .5"), "1000.5");
+        assert_eq!(eval("2 ** 0.5 > 1.41"), "1");
+    }
+
+    #[test]
+    fn test_literals_and_format() {
+        assert_eq!(eval("0xff + 0b11 + 0o10 + 1_000"), "1266");
+        assert_eq!(format(Number::Int(255), 16, 4).unwrap(), "00ff");
+        assert_eq!(format(Number::Int(-5), 2, 4).unwrap(), "-0101");
+        assert_eq!(format(Number::Float(-2.5), 10, 4).unwrap(), "-02.5");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(evaluate("1 / 0"), Err("Division by zero".to_string()));
+        assert_eq!(evaluate("1.5 & 1"), Err("& needs integers".to_string()));
+        assert_eq!(evaluate("(1 + 2"), Err("Missing )".to_string()));
+        assert_eq!(evaluate("9223372036854775807 + 1"), Err("Integer overflow".to_string()));
+        assert_eq!(evaluate("1 << 63"), Err("Integer overflow".to_string()));
+        assert_eq!(evaluate("3 << 62"), Err("Integer overflow".to_string()));
+        assert_eq!(evaluate("-1 << 63"), Ok(Number::Int(i64::MIN)));
+        assert!(evaluate("1 2").is_err());
+        assert!(format(Number::Float(1.5), 16, 0).is_err());
+        assert_eq!(format(Number::Int(1), 10, 99999999999999), Err("Invalid width 99999999999999, at most 1024".to_string()));
+    }
+}
@@ -1,10 +1,14 @@
-use rquickjs::Ctx;
-use std::collections::HashMap;
-use std::time;
+use rquickjs::{Ctx, Runtime};
+use std::time::{Duration, Instant};
 use crate::insertable::InsertableIterator;
 use std::rc::Rc;
-use crate::scopes::{Scopes, Value};
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use crate::sink::{Sink, StdoutSink};
+use crate::scopes::{Builtin, Scopes, Value};
 use crate::builtin::*;
+use crate::host::setup_host;
+use crate::debugger::Debugger;
 
 #[derive(PartialEq, Eq, Debug)]
 enum AdvanceResult {
@@ -16,61 +20,186 @@ enum AdvanceResult {
     NextArg,
 }
 
+/// Bounds on macro expansion, `None` meaning unlimited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Expansions that can be nested inside each other.
+    pub max_depth: Option<usize>,
+    /// Calls collecting their arguments that can be nested inside each other.
+    /// Each one recurses in Rust, so this catches runaway recursion before it overflows the stack,
+    /// large values need a large stack.
+    pub max_call_depth: Option<usize>,
+    /// Macro calls in total.
+    pub max_expansions: Option<usize>,
+    /// Bytes written to the output.
+    pub max_output: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        return Limits {
+            max_depth: None,
+            max_call_depth: Some(1024),
+            max_expansions: None,
+            max_output: None,
+        }
+    }
+}
+
+/// Bounds on QuickJS, `None` meaning unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct JsLimits {
+    /// Bytes the QuickJS heap can take.
+    pub memory: Option<usize>,
+    /// Time a single evaluation can take.
+    pub timeout: Option<Duration>,
+    deadline: Rc<Cell<Option<Instant>>>,
+}
+
+impl JsLimits {
+    pub fn new(memory: Option<usize>, timeout: Option<Duration>) -> JsLimits {
+        return JsLimits { memory, timeout, deadline: Rc::new(Cell::new(None)) }
+    }
+
+    /// Applies the limits to `runtime`, an `H4` using it must share these same `JsLimits`.
+    /// Cannot be called inside `Context::with`, as the runtime is locked there.
+    pub fn install(&self, runtime: &Runtime) {
+        if let Some(memory) = self.memory {
+            runtime.set_memory_limit(memory);
+        }
+        let deadline = self.deadline.clone();
+        runtime.set_interrupt_handler(Some(Box::new(move || {
+            return deadline.get().is_some_and(|deadline| Instant::now() > deadline)
+        })));
+    }
+}
+
+/// Which macro calls get logged to stderr.
+#[derive(Clone, Debug, Default)]
+pub struct Trace {
+    /// Logs every macro but the `excluded` ones.
+    pub all: bool,
+    pub names: HashSet<String>,
+    pub excluded: HashSet<String>,
+}
+
+impl Trace {
+    pub fn enabled(&self, name: &str) -> bool {
+        return self.names.contains(name) || (self.all && !self.excluded.contains(name))
+    }
+
+    pub fn enable(&mut self, name: &str) {
+        self.names.insert(name.to_string());
+        self.excluded.remove(name);
+    }
+
+    pub fn disable(&mut self, name: &str) {
+        self.names.remove(name);
+        self.excluded.insert(name.to_string());
+    }
+
+    pub fn enable_all(&mut self) {
+        self.all = true;
+        self.excluded.clear();
+    }
+
+    pub fn disable_all(&mut self) {
+        self.all = false;
+        self.names.clear();
+    }
+}
+
+/// Makes text fit in a single trace line.
+fn escape_newlines(text: &str) -> String {
+    return text.replace('\n', "\\n")
+}
+
+/// Formats a macro chain, collapsing repetitions, e.g. `a -> b (x300) -> c`,
+/// and eliding the middle of long chains.
+fn format_chain(chain: &[&str]) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < chain.len() {
+        let mut count = 1;
+        while i + count < chain.len() && chain[i + count] == chain[i] {
+            count += 1;
+        }
+        if count > 1 {
+            parts.push(format!("{} (x{count})", chain[i]));
+        } else {
+            parts.push(chain[i].to_string());
+        }
+        i += count;
+    }
+    if parts.len() > 16 {
+        let omitted = parts.len() - 12;
+        parts.splice(4..parts.len() - 8, [format!("... ({omitted} omitted)")]);
+    }
+    return parts.join(" -> ")
+}
+
 pub struct H4<'a, 'b> {
-    pub iter: InsertableIterator<'b, char>,
-    pub outputs: HashMap<String, String>,
-    pub current_output: String,
+    pub iter: InsertableIterator<'b>,
+    /// Arguments of the calls being collected, innermost last.
+    pub captures: Vec<String>,
+    pub sink: Box<dyn Sink + 'b>,
     pub scopes: Rc<Scopes<'a>>,
     pub ctx: Rc<Ctx<'a>>,
     pub quote_level: usize,
     pub call_level: u32,
+    pub trace: Trace,
+    /// Name of the input being processed, used in trace locations.
+    pub file_name: String,
+    pub debugger: Option<Debugger>,
+    pub limits: Limits,
+    pub expansions: usize,
+    pub output_size: usize,
+    pub js_limits: JsLimits,
+    pub sandboxed: Rc<Cell<bool>>,
+    pub shell_used: Rc<Cell<bool>>,
+    pub dependencies: Rc<RefCell<Vec<String>>>,
+    /// Every registered builtin, in registration order, even if its name was later redefined.
+    pub builtins: Vec<Rc<Builtin<'a>>>,
 
     pub name_chars: String,
     pub quote_start: char,
     pub quote_end: char,
 }
 
-fn new_id() -> String {
-    // TODO: Ensure they are unique
-    let timestamp = time::SystemTime::now()
-        .duration_since(time::UNIX_EPOCH)
-        .expect("Cannot get system time");
-    return format!("temp{}", timestamp.as_micros())
-}
-
 impl<'h, 'b> H4<'h, 'b> {
-    pub fn new(iter: InsertableIterator<'b, char>, ctx: Ctx<'h>) -> H4<'h, 'b> {
-            let outputs = HashMap::new();
-
+    pub fn new(iter: InsertableIterator<'b>, ctx: Ctx<'h>) -> H4<'h, 'b> {
             let scopes = Scopes::new();
-            scopes.let_variable(&"@define".to_string(), Value::Builtin(builtin_define));
-            scopes.let_variable(&"@dump".to_string(), Value::Builtin(builtin_dump));
-            scopes.let_variable(&"@pushScope".to_string(), Value::Builtin(builtin_push_scope));
-            scopes.let_variable(&"@popScope".to_string(), Value::Builtin(builtin_pop_scope));
-            scopes.let_variable(&"@skip".to_string(), Value::Builtin(builtin_skip));
-            scopes.let_variable(&"@jsEval".to_string(), Value::Builtin(builtin_jseval));
-            scopes.let_variable(&"@let".to_string(), Value::Builtin(builtin_let));
-            scopes.let_variable(&"@set".to_string(), Value::Builtin(builtin_set));
-            scopes.let_variable(&"@get".to_string(), Value::Builtin(builtin_get));
-            scopes.let_variable(&"@shell".to_string(), Value::Builtin(builtin_shell));
-            scopes.let_variable(&"@import".to_string(), Value::Builtin(builtin_import));
-            scopes.let_variable(&"@for".to_string(), Value::Builtin(builtin_for));
             scopes.let_variable(&"@newline".to_string(), Value::Plain("\n".to_string()));
 
-            let h4 = H4{
+            let mut h4 = H4{
                 iter,
-                outputs,
+                captures: Vec::new(),
                 scopes: Rc::new(scopes),
                 ctx: Rc::new(ctx),
 
-                current_output: "stdout".to_string(),
+                sink: Box::new(StdoutSink::new()),
                 name_chars: "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_@".to_string(),
                 quote_start: '`',
                 quote_end: '\'',
                 quote_level: 0,
                 call_level: 0,
+                trace: Trace::default(),
+                file_name: "<input>".to_string(),
+                debugger: None,
+                limits: Limits::default(),
+                expansions: 0,
+                output_size: 0,
+                js_limits: JsLimits::default(),
+                sandboxed: Rc::new(Cell::new(false)),
+                shell_used: Rc::new(Cell::new(false)),
+                dependencies: Rc::new(RefCell::new(Vec::new())),
+                builtins: Vec::new(),
             };
 
+            for builtin in builtins() {
+                h4.register(builtin);
+            }
+
             h4.setup_quickjs();
 
             let push = h4.eval_js("h4Push".to_string());
@@ -79,8 +208,33 @@ impl<'h, 'b> H4<'h, 'b> {
             return h4
     }
 
+    /// Makes a builtin available as a macro in the current scope,
+    /// replacing any builtin registered with the same name.
+    pub fn register(&mut self, builtin: Builtin<'h>) {
+        let builtin = Rc::new(builtin);
+        self.builtins.retain(|registered| registered.name != builtin.name);
+        self.builtins.push(builtin.clone());
+        self.scopes.let_variable(&builtin.name.clone(), Value::Builtin(builtin));
+    }
+
+    /// Makes a Rust function available as the macro `name`, taking any number of arguments.
+    pub fn register_builtin<F>(&mut self, name: &str, builtin: F)
+    where F: Fn(&mut H4<'h, '_>, &Vec<String>) -> String + 'h {
+        self.register(Builtin::new(name, 0, None, "", builtin));
+    }
+
+    pub fn builtin(&self, name: &str) -> Option<Rc<Builtin<'h>>> {
+        return self.builtins.iter().find(|builtin| builtin.name == name).cloned()
+    }
+
     pub fn consume(&mut self) {
-        while let Some(_) = self.advance() {}
+        while self.advance().is_some() {}
+    }
+
+    /// Processes another input, keeping the definitions made by previous ones.
+    pub fn process(&mut self, input: Box<dyn Iterator<Item = char> + 'b>) {
+        self.iter = InsertableIterator::from(input);
+        self.consume();
     }
 
     fn setup_quickjs(&self) {
@@ -113,13 +267,29 @@ impl<'h, 'b> H4<'h, 'b> {
                 return value
             })).ok();
 
+        let scopes = self.scopes.clone();
+        let ctx = self.ctx.clone();
+        ctx.globals()
+            .set("h4DeleteVariable", rquickjs::Function::new(*ctx.clone(), move |name: String| {
+                scopes.remove_variable(&name);
+            })).ok();
+
         let ctx = self.ctx.clone();
         ctx.globals()
             .set("debugPrint", rquickjs::Function::new(*ctx.clone(), |value: String| {
                 eprintln!("{}", value)
             })).ok();
+        let sandboxed = self.sandboxed.clone();
+        let shell_used = self.shell_used.clone();
         ctx.globals()
-            .set("shell", rquickjs::Function::new(*ctx.clone(), run_shell)).ok();
+            .set("shell", rquickjs::Function::new(*ctx.clone(), move |ctx: Ctx, command: String| {
+                if sandboxed.get() {
+                    return Err(rquickjs::Exception::throw_message(ctx, "shell: Not allowed in sandbox mode"))
+                }
+                shell_used.set(true);
+                return Ok(run_shell(command))
+            })).ok();
+        setup_host(*ctx.clone(), self.sandboxed.clone(), self.dependencies.clone());
 
         let ctx = self.ctx.clone();
         _ = ctx.eval::<rquickjs::Value, &str>(r#"
@@ -132,6 +302,11 @@ impl<'h, 'b> H4<'h, 'b> {
                 has(_target, key) {
                     key = key.toString()
                     return h4GetVariable(key) !== undefined
+                },
+
+                deleteProperty(_target, key) {
+                    h4DeleteVariable(key.toString())
+                    return true
                 }
             }
 
@@ -148,54 +323,123 @@ impl<'h, 'b> H4<'h, 'b> {
             function h4Eval(script) {
                 return Function("h4Proxy", 'with(h4Proxy) {return (' + script + ')}')(h4Proxy)
             }
+
+            let h4Emitted = []
+
+            function emit(...args) {
+                h4Emitted.push(args.join(""))
+            }
+
+            function h4Run(script) {
+                let previous = h4Emitted // Nested @js blocks get their own buffer
+                h4Emitted = []
+                try {
+                    Function("h4Proxy", 'with(h4Proxy) {' + script + '\n}')(h4Proxy)
+                    return h4Emitted.join("")
+                } finally {
+                    h4Emitted = previous
+                }
+            }
         "#).expect("Cannot intialize QuickJS variables")
     }
 
     fn write(&mut self, chr: char) {
-        if self.current_output == "stdout" {
-            print!("{}", chr); // TODO: Make faster
-        }
-        if self.current_output == "stderr" {
-            eprint!("{}", chr); // TODO: Make faster
+        self.write_str(chr.encode_utf8(&mut [0; 4]));
+    }
+
+    /// Runs a QuickJS evaluation within the time budget,
+    /// panicking with `description` if it fails.
+    fn guard_js<T>(&self, description: &str, evaluation: impl FnOnce() -> rquickjs::Result<T>) -> T {
+        let deadline = &self.js_limits.deadline;
+        let outer = deadline.get(); // Nested evaluations share the outermost budget
+        if outer.is_none() {
+            deadline.set(self.js_limits.timeout.map(|timeout| Instant::now() + timeout));
         }
-        if !self.outputs.contains_key(&self.current_output) {
-            self.outputs.insert(self.current_output.clone(), String::new());
+        let result = evaluation();
+        let timed_out = deadline.get().is_some_and(|deadline| Instant::now() > deadline);
+        deadline.set(outer);
+        match result {
+            Ok(value) => return value,
+            Err(error) => {
+                if timed_out {
+                    panic!(
+                        "{description}: Exceeded the JavaScript time limit of {}ms",
+                        self.js_limits.timeout.unwrap_or_default().as_millis()
+                    )
+                }
+                let error = rquickjs::CaughtError::from_error(*self.ctx, error);
+                let out_of_memory = match &error {
+                    rquickjs::CaughtError::Error(rquickjs::Error::Allocation) => true,
+                    rquickjs::CaughtError::Value(value) => value.is_null(), // QuickJS throws null when it cannot allocate the error
+                    rquickjs::CaughtError::Exception(exception) => exception.message().as_deref() == Some("out of memory"),
+                    _ => false,
+                };
+                if out_of_memory {
+                    if let Some(memory) = self.js_limits.memory {
+                        panic!("{description}: Exceeded the JavaScript memory limit of {memory} bytes")
+                    }
+                }
+                panic!("{description}: {error}")
+            }
         }
-        let output = self.outputs.get_mut(&self.current_output).expect("The value was not inserted");
-        output.push(chr);
     }
 
     pub fn eval_js(&self, js: String) -> rquickjs::Value<'h> {
         let value = self.ctx.eval::<rquickjs::Function, &str>("h4Eval").expect("h4Eval not found");
-        let result: rquickjs::Value = value.call((&js,)).expect("Could not evaluate");
-        return result
+        return self.guard_js(&format!("JavaScript `{js}'"), || value.call((&js,)))
+    }
+
+    pub fn run_js(&self, js: String) -> String {
+        let value = self.ctx.eval::<rquickjs::Function, &str>("h4Run").expect("h4Run not found");
+        let result: rquickjs::String = self.guard_js(&format!("JavaScript block `{js}'"), || value.call((&js,)));
+        return result.to_string().unwrap_or_else(|e| panic!("QuickJS Error: {e}"));
+    }
+
+    /// Evaluates JavaScript in the global scope, so declarations stay visible to later code.
+    pub fn eval_global_js(&self, js: &str) {
+        self.guard_js(&format!("JavaScript `{js}'"), || self.ctx.eval::<rquickjs::Value, &str>(js));
     }
 
     pub fn js_value_to_string(&self, value: rquickjs::Value<'h>) -> String {
         let str = self.ctx.eval::<rquickjs::Function, &str>("String").expect("String not found");
-        let result: rquickjs::String = str.call((value,)).expect("Could not evaluate");
+        let result: rquickjs::String = self.guard_js("Converting a value to a string", || str.call((value,)));
         return result.to_string().unwrap_or_else(|e| panic!("QuickJS Error: {e}"));
     }
 
-    fn write_string(&mut self, str: String) {
-        if self.current_output == "stdout" {
-            print!("{}", str); // TODO: Make faster
-        }
-        if self.current_output == "stderr" {
-            eprint!("{}", str); // TODO: Make faster
+    fn write_str(&mut self, str: &str) {
+        if let Some(capture) = self.captures.last_mut() {
+            capture.push_str(str);
+            return
         }
-        if !self.outputs.contains_key(&self.current_output) {
-            self.outputs.insert(self.current_output.clone(), String::new());
+        self.output_size += str.len();
+        if let Some(max_output) = self.limits.max_output {
+            if self.output_size > max_output {
+                panic!(
+                    "Output limit of {max_output} bytes reached: {}",
+                    format_chain(&self.iter.origins())
+                )
+            }
         }
-        let output = self.outputs.get_mut(&self.current_output).expect("The value was not inserted");
-        output.push_str(&str);
+        self.sink.write_str(str);
+    }
+
+    fn insert_expansion(&mut self, name: &str, str: String) {
+        self.iter.insert_from(str, name);
+        self.check_depth("Expansion depth", self.iter.depth(), self.limits.max_depth);
     }
 
-    fn insert_input(&mut self, str: String) {
-        self.iter.insert_elements(str.chars().collect());
+    fn check_depth(&self, what: &str, depth: usize, max_depth: Option<usize>) {
+        if let Some(max_depth) = max_depth {
+            if depth > max_depth {
+                panic!(
+                    "{what} limit of {max_depth} reached: {}",
+                    format_chain(&self.iter.origins())
+                )
+            }
+        }
     }
 
-    fn eval_macro(&mut self, value: &Value<'h>, args: &Vec<String>) -> String {
+    fn eval_macro(&mut self, name: &str, value: &Value<'h>, args: &Vec<String>) -> String {
         match value {
             Value::Plain(str) => {
                 let mut evaluated = "`'@pushScope\n".to_string();
@@ -206,17 +450,21 @@ impl<'h, 'b> H4<'h, 'b> {
                 evaluated.push_str("`'@popScope\n");
                 return evaluated
             }
-            Value::Builtin(func) => {
-                return func(self, args)
+            Value::Builtin(builtin) => {
+                builtin.check_arity(name, args);
+                return (builtin.function)(self, args)
             }
             Value::JS(value) => {
                 if value.is_function() {
                     let value = value.as_function().unwrap();
                     let caller: rquickjs::Function =
                         self.ctx.eval("(f, args) => f(...args)").unwrap();
-                    let result: rquickjs::Value = caller.call((value.as_value(), args))
-                        .expect("Error executing value");
-                    let as_string = result.as_string().expect("Function must return a string");
+                    let result: rquickjs::Value = self.guard_js(
+                        &format!("JavaScript macro {name}"),
+                        || caller.call((value.as_value(), args))
+                    );
+                    let as_string = result.as_string()
+                        .unwrap_or_else(|| panic!("{name}: Function must return a string"));
                     return as_string.to_string().expect("Function does not return valid string")
                 }
                 return self.js_value_to_string(value.clone());
@@ -225,7 +473,7 @@ impl<'h, 'b> H4<'h, 'b> {
     }
 
     fn advance(&mut self) -> Option<AdvanceResult> {
-        let chr = *self.iter.peek()?;
+        let chr = self.iter.peek()?;
         if self.quote_level > 0 {
             if chr == self.quote_start {
                 self.quote_level += 1
@@ -244,42 +492,65 @@ impl<'h, 'b> H4<'h, 'b> {
             return Some(AdvanceResult::EnterQuote);
         }
         if self.name_chars.contains(chr) {
+            let (line, column) = self.iter.location();
             let name = self.consume_name();
-            let variable = self.scopes.get_variable(&name).map(|x| x.clone());
+            let variable = self.scopes.get_variable(&name);
             match variable {
                 None => {
-                    self.write_string(name);
+                    self.write_str(&name);
                     return Some(AdvanceResult::Normal)
                 }
                 Some(value) => {
                     let mut args: Vec<String> = Vec::new();
-                    if self.iter.peek() == Some(&'(') {
+                    if self.iter.peek() == Some('(') {
                         self.call_level += 1;
                         self.iter.next();
-                        let mut id = new_id();
-                        let previous_output = self.current_output.clone();
-                        self.current_output = id.clone();
+                        self.captures.push(String::new());
+                        self.check_depth("Call depth", self.captures.len(), self.limits.max_call_depth);
                         loop {
                             let reason = self.advance().expect("Did not close call before EOF");
                             if reason == AdvanceResult::CallEnd || reason == AdvanceResult::NextArg {
-                                args.push(
-                                    self.outputs.get(&id)
-                                        .map(|x| x.clone())
-                                        .unwrap_or_else(|| String::new())
-                                );
-                                self.outputs.remove(&id); // TODO: Not borrow
-                                id = new_id();
-                                self.current_output = id.clone();
+                                let capture = self.captures.last_mut().expect("The capture stack is empty");
+                                args.push(std::mem::take(capture));
                             }
                             if reason == AdvanceResult::CallEnd {
                                 break
                             }
                         }
-                        self.current_output = previous_output;
+                        self.captures.pop();
+                    }
+                    self.expansions += 1;
+                    if let Some(max_expansions) = self.limits.max_expansions {
+                        if self.expansions > max_expansions {
+                            panic!(
+                                "Expansion limit of {max_expansions} reached at {name}: {}",
+                                format_chain(&self.iter.origins())
+                            )
+                        }
+                    }
+                    if let Some(mut debugger) = self.debugger.take() {
+                        if debugger.should_stop(&name) {
+                            debugger.stop(self, &name, &args, (line, column));
+                        }
+                        self.debugger = Some(debugger);
+                    }
+                    let value = value.borrow().clone();
+                    let mut evaluated = self.eval_macro(&name, &value, &args);
+                    if self.trace.enabled(&name) {
+                        let expansion = match &value {
+                            Value::Plain(body) => body, // Leaves out the scope wrapping the arguments
+                            _ => &evaluated,
+                        };
+                        eprintln!(
+                            "{}:{line}:{column}: [{}] {name}({}) -> `{}'",
+                            self.file_name,
+                            self.iter.depth(),
+                            escape_newlines(&args.join(", ")),
+                            escape_newlines(expansion),
+                        );
                     }
-                    let mut evaluated = self.eval_macro(&value.borrow(), &args);
                     evaluated.push_str("`'");
-                    self.insert_input(evaluated);
+                    self.insert_expansion(&name, evaluated);
                     return Some(AdvanceResult::Macro)
                 }
             }
@@ -308,7 +579,7 @@ impl<'h, 'b> H4<'h, 'b> {
             if chr.is_none() {
                 break
             }
-            let chr = *chr.unwrap();
+            let chr = chr.unwrap();
             if !self.name_chars.contains(chr) {
                 break
             }
@@ -318,3 +589,208 @@ impl<'h, 'b> H4<'h, 'b> {
         return name
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::BufferSink;
+    use rquickjs::{Runtime, Context};
+
+    fn expand(input: &str) -> String {
+        return expand_with(input, |_| {})
+    }
+
+    fn expand_with(input: &str, configure: impl FnOnce(&mut H4)) -> String {
+        return expand_limited(input, JsLimits::default(), configure)
+    }
+
+    /// Same as `expand_with`, on a runtime bounded by `js_limits`.
+    fn expand_limited(input: &str, js_limits: JsLimits, configure: impl FnOnce(&mut H4)) -> String {
+        let runtime = Runtime::new().unwrap();
+        js_limits.install(&runtime);
+        let context = Context::full(&runtime).unwrap();
+        let sink = BufferSink::new();
+        context.with(|ctx| {
+            let boxed: Box<dyn Iterator<Item = char>> = Box::new(input.chars());
+            let mut h4 = H4::new(InsertableIterator::from(boxed), ctx);
+            h4.sink = Box::new(sink.clone());
+            h4.js_limits = js_limits;
+            configure(&mut h4);
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| h4.consume()));
+            h4.scopes.clear();
+            if let Err(payload) = result {
+                std::panic::resume_unwind(payload);
+            }
+        });
+        return sink.contents()
+    }
+
+    #[test]
+    fn test_nested_calls() {
+        let input = "@define(`pair', `[@arg0 @arg1]')\npair(pair(a, b), pair(c, pair(d, e)))";
+        assert_eq!(expand(input), "[[a b] [c [d e]]]");
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut trace = Trace::default();
+        trace.enable("a");
+        assert!(trace.enabled("a") && !trace.enabled("b"));
+        trace.enable_all();
+        trace.disable("a");
+        assert!(!trace.enabled("a") && trace.enabled("b"));
+        trace.disable_all();
+        assert!(!trace.enabled("b"));
+    }
+
+    #[test]
+    fn test_closure_builtin() {
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let output = expand_with("@count(a) @count(b)", move |h4| {
+            h4.register_builtin("@count", move |_h4, args| {
+                counter.set(counter.get() + 1);
+                return format!("{}{}", args[0], counter.get())
+            });
+        });
+        assert_eq!(output, "a1 b2");
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "@define: Expected 2 arguments, got 1")]
+    fn test_arity() {
+        expand("@define(`x')\n");
+    }
+
+    #[test]
+    fn test_empty_argument() {
+        assert_eq!(expand("@len(`') @define(`f', `[@upper(@arg0)]')\nf() @traceOff()\nx"), "0 [] x");
+    }
+
+    #[test]
+    fn test_help() {
+        assert_eq!(expand("@help(`@skip')"), "@skip([count]): Drops the next count chars of input, 1 by default");
+    }
+
+    #[test]
+    fn test_string_builtins() {
+        assert_eq!(expand("@upper(@substr(@replace(a-b-c, -, _), 1, 3))"), "_B_");
+        assert_eq!(expand("@define(`last', `@arg2')\nlast(@split(`x;y;z', ;)) @len(héllo)"), "z 5");
+    }
+
+    #[test]
+    fn test_regex_builtins() {
+        assert_eq!(expand("@regexReplace(vec_add, `_(\\w)', `-$1')"), "vec-add");
+        assert_eq!(expand("@regexMatch(abc, `^a', yes, no) @regexMatch(abc, `^b', yes, no)"), "yes no");
+        let input = "@regexCapture(`2024-05', `(?P<year>\\d+)-(\\d+)', `@capture_year/@capture2')";
+        assert_eq!(expand(input), "2024/05");
+    }
+
+    #[test]
+    fn test_undefine() {
+        let input = "@define(`a', 1)\n@pushScope\n@define(`a', 2)\n@undefine(`a')\na @define(`a', 3)\n@undefineAll(`a')\na";
+        assert_eq!(expand(input), "1 a");
+        assert_eq!(expand("@let(`x', 1)\n@js(`delete x; emit(typeof x)')"), "undefined");
+    }
+
+    #[test]
+    fn test_js_block() {
+        let input = "@js(`let n = 2; for (let i = 0; i < n; i++) emit(i, \"-\"); emit(h4Run(\"emit(7); emit(8)\"), \"!\"); emit(n)')";
+        assert_eq!(expand(input), "0-1-78!2");
+    }
+
+    #[test]
+    #[should_panic(expected = "fs.writeFile: Cannot write out.txt in sandbox mode")]
+    fn test_sandbox_write_file() {
+        expand_with("@js(`fs.writeFile(\"out.txt\", \"x\")')", |h4| h4.sandboxed.set(true));
+    }
+
+    #[test]
+    #[should_panic(expected = "shell: Not allowed in sandbox mode")]
+    fn test_sandbox_shell() {
+        expand_with("@jsEval(`shell(\"echo x\")')", |h4| h4.sandboxed.set(true));
+    }
+
+    #[test]
+    fn test_read_file_dependency() {
+        let mut dependencies = None;
+        let output = expand_with("@jsEval(`fs.readFile(\"Cargo.toml\").startsWith(\"[package]\")')", |h4| {
+            dependencies = Some(h4.dependencies.clone());
+        });
+        assert_eq!(output, "true");
+        assert_eq!(*dependencies.unwrap().borrow(), vec!["Cargo.toml".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "@eval: The radix must be a non-negative integer, got `4294967298'")]
+    fn test_eval_radix() {
+        expand("@eval(10, 4294967298)");
+    }
+
+    #[test]
+    fn test_eval_parentheses() {
+        assert_eq!(expand("@eval(`(1 + 2) * 3', 16, 2)"), "09");
+    }
+
+    #[test]
+    fn test_pushdef() {
+        let input = "@define(`a', 1)\n@pushdef(`a', 2)\n@define(`b', x)\n@pushScope\n@pushdef(`a', 3)\n@pushdef(`c', 4)\n@popScope\na b c @popdef(`a')\na @popdef(`a', `c')\na c";
+        assert_eq!(expand(input), "3 x 4 2 1 c");
+    }
+
+    #[test]
+    fn test_pushdef_scopes() {
+        let crossing = "@define(`a', 1)\n@pushScope\n@define(`a', 5)\n@pushdef(`a', 2)\n@popScope\n@popdef(`a')\na";
+        assert_eq!(expand(crossing), "1");
+        let undefined = "@define(`a', 1)\n@pushdef(`a', 2)\n@undefine(`a')\n@popdef(`a')\n[a]";
+        assert_eq!(expand(undefined), "[a]");
+    }
+
+    #[test]
+    fn test_format_chain() {
+        assert_eq!(format_chain(&["a", "b", "b", "b", "c"]), "a -> b (x3) -> c");
+        let alternating: Vec<&str> = (0..40).map(|i| if i % 2 == 0 { "a" } else { "b" }).collect();
+        assert_eq!(
+            format_chain(&alternating),
+            "a -> b -> a -> b -> ... (28 omitted) -> a -> b -> a -> b -> a -> b -> a -> b"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Expansion depth limit of 100 reached: loop (x100)")]
+    fn test_recursion_limit() {
+        expand_with("@define(`loop', `loop')\nloop", |h4| h4.limits.max_depth = Some(100));
+    }
+
+    #[test]
+    #[should_panic(expected = "Call depth limit of 1024 reached")]
+    fn test_argument_nesting_limit() {
+        expand(&format!("@define(`b', `[@arg0]')\n{}x{}", "b(".repeat(2000), ")".repeat(2000)));
+    }
+
+    #[test]
+    fn test_tail_recursion() {
+        let input = "@define(`down', `@regexMatch(@arg0, `^0$', `done', `down(@eval(@arg0 - 1))')')\ndown(5000)";
+        assert_eq!(expand(input), "done");
+    }
+
+    #[test]
+    #[should_panic(expected = "JavaScript block `while (true) {}': Exceeded the JavaScript time limit of 50ms")]
+    fn test_js_timeout() {
+        let js_limits = JsLimits::new(None, Some(Duration::from_millis(50)));
+        expand_limited("@js(`while (true) {}')", js_limits, |_| {});
+    }
+
+    #[test]
+    #[should_panic(expected = "Exceeded the JavaScript memory limit of 10000000 bytes")]
+    fn test_js_memory_limit() {
+        let js_limits = JsLimits::new(Some(10_000_000), None);
+        expand_limited("@jsEval(`\"x\".repeat(1e8).length')", js_limits, |_| {});
+    }
+
+    #[test]
+    fn test_buffer_sink() {
+        assert_eq!(expand("@define(`word', `APPLE')\nword `word'\n"), "APPLE word\n");
+    }
+}
@@ -0,0 +1,99 @@
+use rquickjs::{Ctx, Exception, Function, function::Rest};
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+fn throw(ctx: Ctx, message: String) -> rquickjs::Error {
+    return Exception::throw_message(ctx, &message)
+}
+
+fn exists(path: String) -> bool {
+    return Path::new(&path).exists()
+}
+
+fn read_dir(ctx: Ctx, path: String) -> rquickjs::Result<Vec<String>> {
+    let entries = fs::read_dir(&path)
+        .map_err(|e| throw(ctx, format!("fs.readDir: Could not read {path}: {e}")))?;
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    return Ok(names)
+}
+
+fn glob(ctx: Ctx, pattern: String) -> rquickjs::Result<Vec<String>> {
+    let paths = glob::glob(&pattern)
+        .map_err(|e| throw(ctx, format!("fs.glob: Invalid pattern {pattern}: {e}")))?;
+    let mut matches: Vec<String> = paths
+        .filter_map(|path| path.ok())
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    matches.sort();
+    return Ok(matches)
+}
+
+fn path_join(parts: Rest<String>) -> String {
+    let mut path = PathBuf::new();
+    for part in parts.into_inner() {
+        path.push(part);
+    }
+    return path.to_string_lossy().to_string()
+}
+
+fn path_dirname(path: String) -> String {
+    return Path::new(&path)
+        .parent()
+        .map(|parent| parent.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Registers the `fs`, `path` and `process` globals.
+/// Writing files is refused while `sandboxed` is set, files read are added to `dependencies`.
+pub fn setup_host(ctx: Ctx, sandboxed: Rc<Cell<bool>>, dependencies: Rc<RefCell<Vec<String>>>) {
+    let globals = ctx.globals();
+    globals.set("h4ReadFile", Function::new(ctx, move |ctx: Ctx, path: String| -> rquickjs::Result<String> {
+        let text = fs::read_to_string(&path)
+            .map_err(|e| throw(ctx, format!("fs.readFile: Could not read {path}: {e}")))?;
+        dependencies.borrow_mut().push(path);
+        return Ok(text)
+    })).ok();
+    globals.set("h4WriteFile", Function::new(ctx, move |ctx: Ctx, path: String, content: String| {
+        if sandboxed.get() {
+            return Err(throw(ctx, format!("fs.writeFile: Cannot write {path} in sandbox mode")))
+        }
+        return fs::write(&path, content)
+            .map_err(|e| throw(ctx, format!("fs.writeFile: Could not write {path}: {e}")))
+    })).ok();
+    globals.set("h4Exists", Function::new(ctx, exists)).ok();
+    globals.set("h4ReadDir", Function::new(ctx, read_dir)).ok();
+    globals.set("h4Glob", Function::new(ctx, glob)).ok();
+    globals.set("h4PathJoin", Function::new(ctx, path_join)).ok();
+    globals.set("h4PathDirname", Function::new(ctx, path_dirname)).ok();
+
+    let env: std::collections::HashMap<String, String> = std::env::vars().collect();
+    let argv: Vec<String> = std::env::args().collect();
+    globals.set("h4Env", env).ok();
+    globals.set("h4Argv", argv).ok();
+
+    _ = ctx.eval::<rquickjs::Value, &str>(r#"
+        let fs = {
+            readFile: h4ReadFile,
+            writeFile: h4WriteFile,
+            exists: h4Exists,
+            readDir: h4ReadDir,
+            glob: h4Glob,
+        }
+
+        let path = {
+            join: h4PathJoin,
+            dirname: h4PathDirname,
+        }
+
+        let process = {
+            env: h4Env,
+            argv: h4Argv,
+        }
+    "#).expect("Cannot initialize QuickJS host functions")
+}
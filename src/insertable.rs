@@ -1,51 +1,115 @@
 use std::iter::Peekable;
-use std::collections::VecDeque;
+use std::borrow::Cow;
 
-pub struct InsertableIterator<'a, T> {
-    inserted: VecDeque<T>,
-    iter: Peekable<Box<dyn Iterator<Item = T> + 'a>>,
+struct Buffer<'a> {
+    text: Cow<'a, str>,
+    position: usize,
+    origin: Option<String>,
 }
 
-impl<T> InsertableIterator<'_, T> {
-    pub fn insert_elements(&mut self, input: Vec<T>) {
-        // TODO: Make faster
-        for el in input.into_iter().rev() {
-            self.inserted.push_front(el);
+/// A char iterator that allows inserting text in front of the remaining input.
+/// Inserted text is kept as a stack of buffers with cursors,
+/// so inserting is O(1) regardless of the length of the text.
+pub struct InsertableIterator<'a> {
+    buffers: Vec<Buffer<'a>>,
+    iter: Peekable<Box<dyn Iterator<Item = char> + 'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> InsertableIterator<'a> {
+    pub fn insert_str<S: Into<Cow<'a, str>>>(&mut self, text: S) {
+        self.insert_buffer(text.into(), None);
+    }
+
+    /// Inserts text produced by `origin`, usually the name of a macro.
+    pub fn insert_from<S: Into<Cow<'a, str>>>(&mut self, text: S, origin: &str) {
+        self.insert_buffer(text.into(), Some(origin.to_string()));
+    }
+
+    fn insert_buffer(&mut self, text: Cow<'a, str>, origin: Option<String>) {
+        if text.is_empty() {
+            return // Buffers in the stack are never exhausted, so peek can look at the top only
         }
+        self.buffers.push(Buffer { text, position: 0, origin });
+    }
+
+    /// Number of inserted buffers that are still being read.
+    pub fn depth(&self) -> usize {
+        return self.buffers.len()
+    }
+
+    /// Origins of the buffers that are still being read, outermost first.
+    pub fn origins(&self) -> Vec<&str> {
+        return self.buffers.iter()
+            .filter_map(|buffer| buffer.origin.as_deref())
+            .collect()
     }
 
-    pub fn peek(&mut self) -> Option<&T> {
-        if self.inserted.len() > 0 {
-            return self.inserted.front();
+    /// Up to `length` chars of the text still to be read, without consuming it.
+    /// Only the next char of the underlying input is shown, as reading ahead would consume it.
+    pub fn preview(&mut self, length: usize) -> String {
+        let mut preview: String = self.buffers.iter().rev()
+            .flat_map(|buffer| buffer.text[buffer.position..].chars())
+            .take(length)
+            .collect();
+        if preview.chars().count() < length {
+            preview.extend(self.iter.peek());
         }
-        else {
-            return self.iter.peek();
+        return preview
+    }
+
+    /// Line and column, starting at 1, of the next char of the underlying input.
+    /// Inserted text does not move it.
+    pub fn location(&self) -> (usize, usize) {
+        return (self.line, self.column)
+    }
+
+    pub fn peek(&mut self) -> Option<char> {
+        match self.buffers.last() {
+            Some(buffer) => return buffer.text[buffer.position..].chars().next(),
+            None => return self.iter.peek().copied(),
         }
     }
 
-    pub fn new<'a>(iter: Box<dyn Iterator<Item = T> + 'a>) -> InsertableIterator<'a, T> {
+    pub fn new(iter: Box<dyn Iterator<Item = char> + 'a>) -> InsertableIterator<'a> {
         return InsertableIterator {
             iter: iter.peekable(),
-            inserted: VecDeque::new(),
+            buffers: Vec::new(),
+            line: 1,
+            column: 1,
         }
     }
 }
 
-impl<T> Iterator for InsertableIterator<'_, T> {
-    type Item = T;
-    
-    fn next(&mut self) -> Option<T> {
-        if self.inserted.len() > 0 {
-            return self.inserted.pop_front();
-        }
-        else {
-            return self.iter.next();
+impl Iterator for InsertableIterator<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let buffer = match self.buffers.last_mut() {
+            Some(buffer) => buffer,
+            None => {
+                let chr = self.iter.next()?;
+                if chr == '\n' {
+                    self.line += 1;
+                    self.column = 1;
+                } else {
+                    self.column += 1;
+                }
+                return Some(chr)
+            }
+        };
+        let chr = buffer.text[buffer.position..].chars().next()?;
+        buffer.position += chr.len_utf8();
+        if buffer.position >= buffer.text.len() {
+            self.buffers.pop();
         }
+        return Some(chr)
     }
 }
 
-impl<'a, T> std::convert::From<Box<dyn Iterator<Item = T> + 'a>> for InsertableIterator<'a, T> {
-    fn from(iter: Box<dyn Iterator<Item = T> + 'a>) -> Self {
+impl<'a> std::convert::From<Box<dyn Iterator<Item = char> + 'a>> for InsertableIterator<'a> {
+    fn from(iter: Box<dyn Iterator<Item = char> + 'a>) -> Self {
         return Self::new(iter);
     }
 }
@@ -54,55 +118,97 @@ impl<'a, T> std::convert::From<Box<dyn Iterator<Item = T> + 'a>> for InsertableI
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_iteration() {
-        let values = vec![0, 1, 2, 3, 4, 5];
-        let iterator = values.into_iter();
-        let mut insertable = InsertableIterator::new(Box::new(iterator));
-
-        for i in 0..6 {
+    fn assert_yields(insertable: &mut InsertableIterator, expected: &str) {
+        for chr in expected.chars() {
             let peeked = insertable.peek();
-            assert_eq!(peeked, Some(&i));
+            assert_eq!(peeked, Some(chr));
             let consumed = insertable.next();
-            assert_eq!(consumed, Some(i));
+            assert_eq!(consumed, Some(chr));
         }
+        assert_eq!(insertable.peek(), None);
+        assert_eq!(insertable.next(), None);
     }
 
     #[test]
-    fn test_insert() {
-        let values = vec![3, 4, 5];
-        let iterator = values.into_iter();
+    fn test_iteration() {
+        let iterator = "012345".chars();
         let mut insertable = InsertableIterator::new(Box::new(iterator));
-        insertable.insert_elements(vec![0, 1, 2]);
+        assert_yields(&mut insertable, "012345");
+    }
 
-        for i in 0..6 {
-            let peeked = insertable.peek();
-            assert_eq!(peeked, Some(&i));
-            let consumed = insertable.next();
-            assert_eq!(consumed, Some(i));
-        }
+    #[test]
+    fn test_insert() {
+        let iterator = "345".chars();
+        let mut insertable = InsertableIterator::new(Box::new(iterator));
+        insertable.insert_str("012");
+        assert_yields(&mut insertable, "012345");
     }
 
     #[test]
     fn test_insert_mid_iter() {
-        let values = vec![3, 4, 5];
-        let iterator = values.into_iter();
+        let iterator = "345".chars();
         let mut insertable = InsertableIterator::new(Box::new(iterator));
-        insertable.insert_elements(vec![0, 1, 2]);
+        insertable.insert_str("012");
 
         insertable.next();
         insertable.next();
         insertable.next();
         insertable.next();
 
-        insertable.insert_elements(vec![0, 1, 2, 3]);
+        insertable.insert_str("0123".to_string());
+        assert_yields(&mut insertable, "012345");
+    }
 
-        for i in 0..6 {
-            let peeked = insertable.peek();
-            assert_eq!(peeked, Some(&i));
-            let consumed = insertable.next();
-            assert_eq!(consumed, Some(i));
-        }
+    #[test]
+    fn test_insert_mid_buffer() {
+        let iterator = "!".chars();
+        let mut insertable = InsertableIterator::new(Box::new(iterator));
+        insertable.insert_str("añb");
+        insertable.next();
+        insertable.next();
+        insertable.insert_str("");
+        insertable.insert_str("€ ");
+        assert_yields(&mut insertable, "€ b!");
+    }
+
+    #[test]
+    fn test_origins() {
+        let mut insertable = InsertableIterator::new(Box::new("".chars()));
+        insertable.insert_from("ab", "outer");
+        insertable.next();
+        insertable.insert_str("c");
+        insertable.insert_from("d", "inner");
+        assert_eq!(insertable.depth(), 3);
+        assert_eq!(insertable.origins(), vec!["outer", "inner"]);
+        insertable.next();
+        assert_eq!(insertable.origins(), vec!["outer"]);
+    }
+
+    #[test]
+    fn test_preview() {
+        let mut insertable = InsertableIterator::new(Box::new("xyz".chars()));
+        insertable.insert_str("abc");
+        insertable.next();
+        insertable.insert_str("d");
+        assert_eq!(insertable.preview(2), "db");
+        assert_eq!(insertable.preview(10), "dbcx");
+        assert_yields(&mut insertable, "dbcxyz");
+    }
+
+    #[test]
+    fn test_location() {
+        let mut insertable = InsertableIterator::new(Box::new("ab\ncd".chars()));
+        insertable.next();
+        insertable.insert_str("x\ny");
+        insertable.next();
+        insertable.next();
+        assert_eq!(insertable.location(), (1, 2));
+        insertable.next();
+        insertable.next();
+        insertable.next();
+        assert_eq!(insertable.location(), (2, 1));
+        insertable.next();
+        assert_eq!(insertable.location(), (2, 2));
     }
 
     #[test]
@@ -112,6 +218,6 @@ mod tests {
 
         let mut iterator = InsertableIterator::from(boxed);
 
-        while iterator.next() != None {}
+        while iterator.next().is_some() {}
     }
 }
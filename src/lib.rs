@@ -0,0 +1,18 @@
+#![allow(clippy::needless_return, clippy::ptr_arg)]
+
+pub mod h4;
+pub mod insertable;
+pub mod scopes;
+pub mod builtin;
+pub mod case;
+pub mod host;
+pub mod data;
+pub mod debugger;
+pub mod depfile;
+pub mod eval;
+pub mod dump;
+pub mod repl;
+pub mod reader;
+pub mod sink;
+
+pub use crate::h4::H4;
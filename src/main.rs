@@ -1,36 +1,288 @@
-mod h4;
-mod insertable;
-mod scopes;
-mod builtin;
-
-use h4::H4;
-use rquickjs::{Runtime, Context};
-use insertable::InsertableIterator;
+#![allow(clippy::needless_return, clippy::ptr_arg)]
+
+use h4::{H4, builtin, data, depfile, dump, repl};
+use h4::h4::JsLimits;
+use h4::debugger::Debugger;
+use h4::scopes::Value;
+use h4::insertable::InsertableIterator;
+use h4::reader::ReaderChars;
+use h4::sink::FileSink;
+use rquickjs::{Runtime, Context, Ctx};
 use arg::{Args, parse_args};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 #[derive(Args, Debug)]
 ///h4
 struct Arguments {
-    ///File to be processed. If not specified, stdin is used.
-    file: String
+    #[arg(short = "o", long = "output")]
+    ///File to write the output to, replaced only once generation succeeds. If not specified, stdout is used.
+    output: Option<String>,
+
+    #[arg(short = "e", long = "expression")]
+    ///Processes a snippet before the input files, sharing their definitions.
+    expression: Vec<String>,
+
+    #[arg(long = "js")]
+    ///Runs JavaScript setup code in the global scope before any input.
+    js: Vec<String>,
+
+    #[arg(long)]
+    ///Disallow shell commands and writing files from templates.
+    sandbox: bool,
+
+    #[arg(short = "D", long = "define")]
+    ///Defines a macro as plain text, in the form NAME=VALUE.
+    define: Vec<String>,
+
+    #[arg(short = "J", long = "js-define")]
+    ///Defines a variable from a JavaScript expression, in the form NAME=EXPRESSION.
+    js_define: Vec<String>,
+
+    #[arg(long)]
+    ///Loads a JSON, TOML, YAML or CSV file (by extension) as a variable named after the file, or NAME=PATH.
+    data: Vec<String>,
+
+    #[arg(short = "M", long = "depfile")]
+    ///Writes a Make dependency file for the output to this path. Requires -o.
+    depfile: Option<String>,
+
+    #[arg(long = "depfile-force")]
+    ///Makes the dependency file target always stale if @shell was used.
+    depfile_force: bool,
+
+    #[arg(long = "max-depth")]
+    ///Maximum nesting of macro expansions, 0 for no limit. Unlimited by default.
+    max_depth: Option<usize>,
+
+    #[arg(long = "max-call-depth")]
+    ///Maximum nesting of calls collecting their arguments, 0 for no limit. Defaults to 1024.
+    max_call_depth: Option<usize>,
+
+    #[arg(long = "max-expansions")]
+    ///Maximum number of macro calls, 0 for no limit. Unlimited by default.
+    max_expansions: Option<usize>,
+
+    #[arg(long = "max-output")]
+    ///Maximum size of the output in bytes, 0 for no limit. Unlimited by default.
+    max_output: Option<usize>,
+
+    #[arg(long = "js-memory")]
+    ///Maximum size of the JavaScript heap in bytes. Unlimited by default.
+    js_memory: Option<usize>,
+
+    #[arg(long = "js-timeout")]
+    ///Maximum time in milliseconds a single JavaScript evaluation can take. Unlimited by default.
+    js_timeout: Option<u64>,
+
+    #[arg(long)]
+    ///Logs every macro call to stderr, with its location, arguments and expansion.
+    trace: bool,
+
+    #[arg(long)]
+    ///Stops before every macro call in an interactive debugger reading from the terminal.
+    debug: bool,
+
+    #[arg(long = "list-builtins")]
+    ///Prints every builtin macro with its help and exits.
+    list_builtins: bool,
+
+    #[arg(long = "dump-state")]
+    ///Prints every scope as JSON to stderr once done, even if processing failed.
+    dump_state: bool,
+
+    #[arg(long)]
+    ///Starts an interactive session instead of processing files.
+    repl: bool,
+
+    #[arg(long)]
+    ///Regenerates the output whenever an input or a file read by it changes.
+    watch: bool,
+
+    ///Files to be processed in sequence, sharing definitions. If not specified, stdin is used.
+    files: Vec<String>,
 }
 
-fn main() {
-    let args: Arguments = parse_args();
-    let input = if args.file == "" {
-        std::io::read_to_string(std::io::stdin()).unwrap()
-    } else {
-        std::io::read_to_string(std::fs::File::open(args.file).unwrap()).unwrap()
-    };
+fn split_definition(definition: &str) -> (String, String) {
+    match definition.split_once('=') {
+        Some((name, value)) => (name.to_string(), value.to_string()),
+        None => (definition.to_string(), String::new()),
+    }
+}
 
+fn read_input(file: &str) -> Box<dyn Iterator<Item = char>> {
+    if file == "-" {
+        return Box::new(ReaderChars::new(std::io::stdin().lock()))
+    }
+    let file = File::open(file).unwrap_or_else(|e| panic!("Could not read {file}: {e}"));
+    return Box::new(ReaderChars::new(BufReader::new(file)))
+}
+
+fn limit(value: usize) -> Option<usize> {
+    return if value == 0 { None } else { Some(value) }
+}
+
+fn js_limits(args: &Arguments) -> JsLimits {
+    return JsLimits::new(args.js_memory, args.js_timeout.map(Duration::from_millis))
+}
+
+fn setup<'a>(h4: &mut H4<'a, '_>, args: &Arguments, ctx: Ctx<'a>) {
+    h4.sandboxed.set(args.sandbox);
+    if args.trace {
+        h4.trace.enable_all();
+    }
+    if args.debug {
+        h4.debugger = Some(Debugger::new().unwrap_or_else(|_| Debugger::from_stdin())); // Checked by check_arguments
+    }
+    if let Some(max_depth) = args.max_depth {
+        h4.limits.max_depth = limit(max_depth);
+    }
+    if let Some(max_call_depth) = args.max_call_depth {
+        h4.limits.max_call_depth = limit(max_call_depth);
+    }
+    if let Some(max_expansions) = args.max_expansions {
+        h4.limits.max_expansions = limit(max_expansions);
+    }
+    if let Some(max_output) = args.max_output {
+        h4.limits.max_output = limit(max_output);
+    }
+    if let Some(output) = &args.output {
+        let sink = FileSink::create(output)
+            .unwrap_or_else(|e| panic!("Could not create {output}: {e}"));
+        h4.sink = Box::new(sink);
+    }
+    for definition in &args.define {
+        let (name, value) = split_definition(definition);
+        h4.scopes.let_variable(&name, Value::Plain(value));
+    }
+    for definition in &args.js_define {
+        let (name, expression) = split_definition(definition);
+        h4.scopes.let_variable(&name, Value::JS(h4.eval_js(expression)));
+    }
+    for data in &args.data {
+        let (name, path) = data::data_definition(data);
+        h4.dependencies.borrow_mut().push(path.clone());
+        h4.scopes.let_variable(&name, Value::JS(data::load_data(ctx, data::Format::from_path(&path), &path)));
+    }
+    for js in &args.js {
+        h4.eval_global_js(js);
+    }
+    for expression in &args.expression {
+        h4.file_name = "<expression>".to_string();
+        h4.process(Box::new(expression.chars().collect::<Vec<char>>().into_iter()));
+    }
+}
+
+fn generate<'a>(h4: &mut H4<'a, '_>, args: &Arguments, ctx: Ctx<'a>) {
+    setup(h4, args, ctx);
+    if args.files.is_empty() {
+        h4.file_name = "<stdin>".to_string();
+        h4.process(read_input("-"));
+    }
+    for file in &args.files {
+        h4.dependencies.borrow_mut().push(file.clone());
+        h4.file_name = if file == "-" { "<stdin>".to_string() } else { file.clone() };
+        h4.process(read_input(file));
+    }
+    h4.sink.finish();
+
+    if let Some(path) = &args.depfile {
+        let target = args.output.as_ref().expect("Checked by check_arguments");
+        let force = args.depfile_force && h4.shell_used.get();
+        fs::write(path, depfile::make_rule(target, &h4.dependencies.borrow(), force))
+            .unwrap_or_else(|e| panic!("--depfile: Could not write {path}: {e}"));
+    }
+}
+
+/// Runs a full generation, returning every file it read and whether it succeeded.
+fn run(args: &Arguments) -> (Vec<String>, bool) {
     let runtime = Runtime::new().unwrap();
+    let js_limits = js_limits(args);
+    js_limits.install(&runtime);
     let context = Context::full(&runtime).unwrap();
-    let boxed: Box<dyn Iterator<Item = char>> = Box::new(input.chars());
+    let boxed: Box<dyn Iterator<Item = char>> = Box::new(std::iter::empty());
     let insertable = InsertableIterator::from(boxed);
 
-    context.with(move |ctx| {
+    return context.with(|ctx| {
         let mut h4 = H4::new(insertable, ctx);
-        h4.consume();
+        h4.js_limits = js_limits;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| generate(&mut h4, args, ctx)));
+        if args.dump_state {
+            eprintln!("{}", dump::dump_json(&h4)); // The state at the failure helps the most
+        }
         h4.scopes.clear(); // NOTE: Necessary or QuickJS complains about leaked memory
+        let dependencies = h4.dependencies.borrow().clone();
+        return (dependencies, result.is_ok())
     })
 }
+
+fn list_builtins() {
+    let runtime = Runtime::new().unwrap();
+    let context = Context::full(&runtime).unwrap();
+    context.with(|ctx| {
+        let boxed: Box<dyn Iterator<Item = char>> = Box::new(std::iter::empty());
+        let h4 = H4::new(InsertableIterator::from(boxed), ctx);
+        print!("{}", builtin::list_builtins(&h4));
+        h4.scopes.clear();
+    });
+}
+
+fn modification_times(files: &[String]) -> Vec<Option<SystemTime>> {
+    return files.iter()
+        .map(|file| fs::metadata(file).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
+
+fn watch(args: &Arguments) {
+    if args.files.is_empty() {
+        eprintln!("--watch: Input files are required, stdin cannot be watched");
+        process::exit(1);
+    }
+    loop {
+        let (dependencies, _) = run(args);
+        let times = modification_times(&dependencies);
+        while modification_times(&dependencies) == times {
+            thread::sleep(Duration::from_millis(250));
+        }
+        eprintln!("h4: Change detected, regenerating");
+    }
+}
+
+/// Rejects combinations of flags that cannot work, before anything is generated.
+fn check_arguments(args: &Arguments) -> Result<(), String> {
+    if args.depfile.is_some() && args.output.is_none() {
+        return Err("--depfile: An output file (-o) is required, to be the target of the rule".to_string())
+    }
+    let reads_stdin = args.files.is_empty() || args.files.iter().any(|file| file == "-");
+    if args.debug && reads_stdin && Debugger::new().is_err() {
+        return Err("--debug: There is no terminal to read commands from, and stdin is the input".to_string())
+    }
+    return Ok(())
+}
+
+fn main() {
+    let args: Arguments = parse_args();
+    if let Err(message) = check_arguments(&args) {
+        eprintln!("h4: {message}");
+        process::exit(1);
+    }
+    if args.list_builtins {
+        list_builtins();
+        return
+    }
+    if args.repl {
+        repl::repl(&js_limits(&args), &|h4, ctx| setup(h4, &args, ctx));
+        return
+    }
+    if args.watch {
+        watch(&args);
+    }
+    let (_, success) = run(&args);
+    if !success {
+        process::exit(1);
+    }
+}
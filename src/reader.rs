@@ -0,0 +1,84 @@
+use std::io::BufRead;
+
+/// Decodes UTF-8 from a reader lazily, one char at a time,
+/// so input can be processed as it arrives.
+/// Invalid sequences are replaced with U+FFFD.
+pub struct ReaderChars<R: BufRead> {
+    reader: R,
+}
+
+impl<R: BufRead> ReaderChars<R> {
+    pub fn new(reader: R) -> ReaderChars<R> {
+        return ReaderChars { reader }
+    }
+
+    fn peek_byte(&mut self) -> Option<u8> {
+        let buffer = self.reader.fill_buf().unwrap_or_else(|e| panic!("Could not read input: {e}"));
+        return buffer.first().copied()
+    }
+}
+
+fn sequence_length(first: u8) -> usize {
+    match first {
+        0x00..=0x7F => 1,
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF4 => 4,
+        _ => 0,
+    }
+}
+
+impl<R: BufRead> Iterator for ReaderChars<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let first = self.peek_byte()?;
+        self.reader.consume(1);
+
+        let length = sequence_length(first);
+        if length == 0 {
+            return Some(char::REPLACEMENT_CHARACTER)
+        }
+        let mut bytes = [first, 0, 0, 0];
+        for byte in bytes.iter_mut().take(length).skip(1) {
+            match self.peek_byte() {
+                Some(next) if next & 0xC0 == 0x80 => {
+                    *byte = next;
+                    self.reader.consume(1);
+                }
+                _ => return Some(char::REPLACEMENT_CHARACTER),
+            }
+        }
+        return std::str::from_utf8(&bytes[..length])
+            .ok()
+            .and_then(|str| str.chars().next())
+            .or(Some(char::REPLACEMENT_CHARACTER))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decoding() {
+        let input = "añ€𝄞 text".as_bytes();
+        let chars: String = ReaderChars::new(input).collect();
+        assert_eq!(chars, "añ€𝄞 text");
+    }
+
+    #[test]
+    fn test_small_buffer() {
+        let input = "ñ€𝄞".as_bytes();
+        let reader = std::io::BufReader::with_capacity(1, input);
+        let chars: String = ReaderChars::new(reader).collect();
+        assert_eq!(chars, "ñ€𝄞");
+    }
+
+    #[test]
+    fn test_invalid() {
+        let input: &[u8] = &[b'a', 0xFF, 0xE2, 0x82, b'b'];
+        let chars: String = ReaderChars::new(input).collect();
+        assert_eq!(chars, "a\u{FFFD}\u{FFFD}b");
+    }
+}
@@ -0,0 +1,159 @@
+use crate::H4;
+use crate::h4::JsLimits;
+use crate::insertable::InsertableIterator;
+use crate::dump::dump_text;
+use rquickjs::{Runtime, Context, Ctx};
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+const HELP: &str = "Commands:
+  :scopes  Show every scope and its definitions
+  :reset   Discard all definitions and start over
+  :trace   Toggle logging of macro calls to stderr
+  :help    Show this message
+  :quit    Exit the REPL
+Input continues on the next line while a quote or a call is still open.";
+
+enum Session {
+    Reset,
+    Quit,
+}
+
+/// Whether `input` can be processed without running into EOF
+/// inside a quote or the arguments of a call.
+pub fn is_complete(h4: &H4, input: &str) -> bool {
+    let mut quote_level = 0;
+    let mut call_level = 0;
+    let mut chars = input.chars().peekable();
+    while let Some(chr) = chars.next() {
+        if quote_level > 0 {
+            if chr == h4.quote_start {
+                quote_level += 1;
+            } else if chr == h4.quote_end {
+                quote_level -= 1;
+            }
+        } else if chr == h4.quote_start {
+            quote_level += 1;
+        } else if h4.name_chars.contains(chr) {
+            let mut name = chr.to_string();
+            while let Some(&next) = chars.peek() {
+                if !h4.name_chars.contains(next) {
+                    break
+                }
+                name.push(next);
+                chars.next();
+            }
+            if chars.peek() == Some(&'(') && h4.scopes.get_variable(&name).is_some() {
+                call_level += 1;
+                chars.next();
+            }
+        } else if chr == ')' && call_level > 0 {
+            call_level -= 1;
+        }
+    }
+    return quote_level == 0 && call_level == 0
+}
+
+fn prompt(continuation: bool) {
+    print!("{}", if continuation { "... " } else { "h4> " });
+    io::stdout().flush().ok();
+}
+
+fn session(h4: &mut H4) -> Session {
+    let stdin = io::stdin();
+    let mut buffer = String::new();
+    loop {
+        prompt(!buffer.is_empty());
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => return Session::Quit,
+            Ok(_) => {}
+        }
+
+        if buffer.is_empty() && line.trim_start().starts_with(':') {
+            match line.trim() {
+                ":scopes" => eprint!("{}", dump_text(h4)),
+                ":reset" => return Session::Reset,
+                ":trace" => {
+                    if h4.trace.all {
+                        h4.trace.disable_all();
+                    } else {
+                        h4.trace.enable_all();
+                    }
+                    eprintln!("Tracing {}", if h4.trace.all { "enabled" } else { "disabled" });
+                }
+                ":help" => eprintln!("{HELP}"),
+                ":quit" => return Session::Quit,
+                command => eprintln!("Unknown command {command}, try :help"),
+            }
+            continue
+        }
+
+        buffer.push_str(&line);
+        if !is_complete(h4, &buffer) {
+            continue
+        }
+        let input = std::mem::take(&mut buffer);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            h4.process(Box::new(input.chars().collect::<Vec<char>>().into_iter()));
+        }));
+        if result.is_err() {
+            // The panic message was already printed, leave the parser in a usable state
+            h4.quote_level = 0;
+            h4.call_level = 0;
+            h4.captures.clear();
+        }
+        h4.sink.flush();
+    }
+}
+
+/// Reads input line by line, printing expansions as soon as they are complete.
+/// `setup` runs on every new instance, including after `:reset`.
+pub fn repl(js_limits: &JsLimits, setup: &dyn for<'a, 'b> Fn(&mut H4<'a, 'b>, Ctx<'a>)) {
+    eprintln!("h4 REPL, type :help for commands");
+    loop {
+        let runtime = Runtime::new().unwrap();
+        js_limits.install(&runtime);
+        let context = Context::full(&runtime).unwrap();
+        let result = context.with(|ctx| {
+            let boxed: Box<dyn Iterator<Item = char>> = Box::new(std::iter::empty());
+            let mut h4 = H4::new(InsertableIterator::from(boxed), ctx);
+            h4.js_limits = js_limits.clone();
+            setup(&mut h4, ctx);
+            let result = session(&mut h4);
+            h4.scopes.clear(); // NOTE: Necessary or QuickJS complains about leaked memory
+            return result
+        });
+        match result {
+            Session::Reset => eprintln!("State reset"),
+            Session::Quit => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_h4(f: impl Fn(&H4)) {
+        let runtime = Runtime::new().unwrap();
+        let context = Context::full(&runtime).unwrap();
+        context.with(|ctx| {
+            let boxed: Box<dyn Iterator<Item = char>> = Box::new(std::iter::empty());
+            let h4 = H4::new(InsertableIterator::from(boxed), ctx);
+            f(&h4);
+            h4.scopes.clear();
+        });
+    }
+
+    #[test]
+    fn test_is_complete() {
+        with_h4(|h4| {
+            assert!(is_complete(h4, "plain (text)\n"));
+            assert!(is_complete(h4, "@define(`a', `b')\n"));
+            assert!(!is_complete(h4, "@define(`a', `b\n"));
+            assert!(!is_complete(h4, "@define(`a',\n"));
+            assert!(is_complete(h4, "`nested `quotes'' ok\n"));
+        });
+    }
+}
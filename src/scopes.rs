@@ -3,23 +3,125 @@ use std::rc::Rc;
 use std::collections::HashMap;
 use std::cell::RefCell;
 
+/// Macro implemented in Rust, which can capture state from the host application.
+pub type BuiltinFn<'a> = Rc<dyn Fn(&mut H4<'a, '_>, &Vec<String>) -> String + 'a>;
+
+/// A builtin macro and the description used to validate and document its calls.
+pub struct Builtin<'a> {
+    pub name: String,
+    pub min_args: usize,
+    /// `None` for any number of arguments.
+    pub max_args: Option<usize>,
+    pub help: String,
+    pub function: BuiltinFn<'a>,
+}
+
+impl<'a> Builtin<'a> {
+    pub fn new<F>(name: &str, min_args: usize, max_args: Option<usize>, help: &str, function: F) -> Builtin<'a>
+    where F: Fn(&mut H4<'a, '_>, &Vec<String>) -> String + 'a {
+        return Builtin {
+            name: name.to_string(),
+            min_args,
+            max_args,
+            help: help.to_string(),
+            function: Rc::new(function),
+        }
+    }
+
+    /// The help text, or just the name for builtins registered without one.
+    pub fn help_line(&self) -> String {
+        if self.help.is_empty() {
+            return self.name.clone()
+        }
+        return self.help.clone()
+    }
+
+    /// Panics unless `args` fits the arity, `name` being the name it was called by.
+    /// An empty call like `@name()` passes one empty argument,
+    /// but counts as no arguments for builtins that need none.
+    pub fn check_arity(&self, name: &str, args: &Vec<String>) {
+        let empty_call = args.len() == 1 && args[0].is_empty();
+        let count = if empty_call && self.min_args == 0 { 0 } else { args.len() };
+        let expected = match self.max_args {
+            Some(max_args) if max_args == self.min_args => format!("{max_args}"),
+            Some(max_args) => format!("{} to {max_args}", self.min_args),
+            None => format!("at least {}", self.min_args),
+        };
+        if count < self.min_args || self.max_args.is_some_and(|max_args| count > max_args) {
+            panic!("{name}: Expected {expected} arguments, got {count}")
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum Value<'a> {
     JS(rquickjs::Value<'a>),
     Plain(String),
-    Builtin(fn(&mut H4<'a, '_>, &Vec<String>) -> String),
+    Builtin(Rc<Builtin<'a>>),
+}
+
+pub type Definition<'a> = Rc<RefCell<Value<'a>>>;
+
+pub type Scope<'a> = HashMap<String, Definition<'a>>;
+
+/// A definition hidden by `push_definition`, `None` where the name was undefined.
+#[derive(Clone)]
+pub struct PushedDefinition<'a> {
+    /// Index of the scope it was replaced in.
+    pub scope: usize,
+    pub previous: Option<Definition<'a>>,
 }
 
 #[derive(Clone)]
 pub struct Scopes<'a> {
-    pub scopes: Rc<RefCell<
-        Vec<HashMap<String, Rc<RefCell<Value<'a>>>>>
-    >>,
+    pub scopes: Rc<RefCell<Vec<Scope<'a>>>>,
+    /// Definitions hidden by `push_definition`, per name, innermost last.
+    pub pushed: Rc<RefCell<HashMap<String, Vec<PushedDefinition<'a>>>>>,
+    /// Indices of the scopes defining each name, ascending,
+    /// so lookups do not walk every scope when recursion stacks up many of them.
+    index: Rc<RefCell<HashMap<String, Vec<usize>>>>,
+}
+
+impl Default for Scopes<'_> {
+    fn default() -> Self {
+        return Self::new()
+    }
 }
 
 impl<'a> Scopes<'a> {
     pub fn new() -> Scopes<'a> {
-        Scopes{scopes: Rc::new(RefCell::new(vec![HashMap::new()]))}
+        Scopes{
+            scopes: Rc::new(RefCell::new(vec![HashMap::new()])),
+            pushed: Rc::new(RefCell::new(HashMap::new())),
+            index: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    fn add_to_index(&self, name: &String, scope: usize) {
+        let mut index = self.index.borrow_mut();
+        let indices = index.entry(name.clone()).or_default();
+        if let Err(position) = indices.binary_search(&scope) {
+            indices.insert(position, scope);
+        }
+    }
+
+    fn remove_from_index(&self, name: &String, scope: usize) {
+        let mut index = self.index.borrow_mut();
+        if let Some(indices) = index.get_mut(name) {
+            if indices.last() == Some(&scope) {
+                indices.pop(); // Usually the innermost scope, as when popping it
+            } else {
+                indices.retain(|&i| i != scope);
+            }
+            if indices.is_empty() {
+                index.remove(name);
+            }
+        }
+    }
+
+    /// Index of the innermost scope defining `name`.
+    fn innermost(&self, name: &String) -> Option<usize> {
+        return self.index.borrow().get(name).and_then(|indices| indices.last().copied())
     }
 
     pub fn push_scope(&self) {
@@ -32,7 +134,14 @@ impl<'a> Scopes<'a> {
         let scopes = Rc::clone(&self.scopes);
         let scopes = &mut scopes.borrow_mut();
         assert!(scopes.len() > 1, "Cannot pop the global scope, push a new one first!");
-        scopes.pop();
+        let popped = scopes.pop().expect("The scope stack is empty");
+        let remaining = scopes.len();
+        for name in popped.keys() {
+            self.remove_from_index(name, remaining);
+        }
+        for stack in self.pushed.borrow_mut().values_mut() {
+            stack.retain(|pushed| pushed.scope < remaining); // Their scope is gone, nothing to restore
+        }
     }
 
     pub fn clear(&self) {
@@ -41,18 +150,13 @@ impl<'a> Scopes<'a> {
         }
         let scopes = Rc::clone(&self.scopes);
         *scopes.borrow_mut() = vec![HashMap::new()];
+        self.pushed.borrow_mut().clear();
+        self.index.borrow_mut().clear();
     }
 
     pub fn get_variable(&self, name: &String) -> Option<Rc<RefCell<Value<'a>>>> {
-        let scopes = Rc::clone(&self.scopes);
-        let scopes = &mut scopes.borrow_mut();
-        for scope in scopes.iter().rev() {
-            if let Some(value) = scope.get(name) {
-                let value = Rc::clone(value);
-                return Some(value);
-            }
-        }
-        return None;
+        let index = self.innermost(name)?;
+        return self.scopes.borrow()[index].get(name).cloned()
     }
 
     pub fn set_variable(&self, name: &String, value: Value<'a>) -> Option<()> {
@@ -61,8 +165,62 @@ impl<'a> Scopes<'a> {
         return Some(())
     }
 
+    /// Removes the definition of `name` from the innermost scope defining it, returning it,
+    /// along with the definitions `push_definition` hid in that scope.
+    pub fn remove_variable(&self, name: &String) -> Option<Value<'a>> {
+        let index = self.innermost(name)?;
+        if let Some(stack) = self.pushed.borrow_mut().get_mut(name) {
+            stack.retain(|pushed| pushed.scope != index);
+        }
+        self.remove_from_index(name, index);
+        let value = self.scopes.borrow_mut()[index].remove(name)?;
+        let value = value.borrow().clone();
+        return Some(value)
+    }
+
+    /// Removes the definitions of `name` from every scope, returning how many there were.
+    pub fn remove_all_variables(&self, name: &String) -> usize {
+        self.pushed.borrow_mut().remove(name);
+        self.index.borrow_mut().remove(name);
+        let scopes = &mut self.scopes.borrow_mut();
+        return scopes.iter_mut()
+            .filter_map(|scope| scope.remove(name))
+            .count()
+    }
+
+    /// Replaces the innermost definition of `name`, keeping the previous one for `pop_definition`.
+    /// Undefined names are defined in the global scope, so they outlive the current one.
+    pub fn push_definition(&self, name: &String, value: Value<'a>) {
+        let index = self.innermost(name).unwrap_or(0);
+        self.add_to_index(name, index);
+        let previous = self.scopes.borrow_mut()[index].insert(name.clone(), Rc::new(RefCell::new(value)));
+        let pushed = PushedDefinition { scope: index, previous };
+        self.pushed.borrow_mut().entry(name.clone()).or_default().push(pushed);
+    }
+
+    /// Restores the definition of `name` hidden by the last `push_definition`,
+    /// in the scope it was hidden in. Does nothing if there is none left.
+    pub fn pop_definition(&self, name: &String) {
+        let pushed = match self.pushed.borrow_mut().get_mut(name).and_then(|stack| stack.pop()) {
+            Some(pushed) => pushed,
+            None => return,
+        };
+        let scopes = &mut self.scopes.borrow_mut();
+        match pushed.previous {
+            Some(definition) => {
+                self.add_to_index(name, pushed.scope);
+                scopes[pushed.scope].insert(name.clone(), definition);
+            }
+            None => {
+                self.remove_from_index(name, pushed.scope);
+                scopes[pushed.scope].remove(name);
+            }
+        }
+    }
+
     pub fn let_variable(&self, name: &String, value: Value<'a>) {
         let scopes = &mut self.scopes.borrow_mut();
+        self.add_to_index(name, scopes.len() - 1);
         let scope = scopes.last_mut().expect("The scope stack is empty");
         scope.insert(name.clone(), Rc::new(RefCell::new(value)));
     }
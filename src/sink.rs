@@ -0,0 +1,178 @@
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Stdout, Write};
+use std::rc::Rc;
+
+/// Destination for the generated output.
+pub trait Sink {
+    fn write_str(&mut self, str: &str);
+    fn flush(&mut self) {}
+
+    /// Called once all the output was written successfully.
+    fn finish(&mut self) {
+        self.flush();
+    }
+
+    /// Where the output goes, for messages like the debugger's.
+    fn describe(&self) -> String {
+        return "a custom sink".to_string()
+    }
+}
+
+/// Buffered stdout, flushed at every newline so piped output arrives as it is generated.
+pub struct StdoutSink {
+    writer: BufWriter<Stdout>,
+}
+
+impl StdoutSink {
+    pub fn new() -> StdoutSink {
+        return StdoutSink { writer: BufWriter::new(io::stdout()) }
+    }
+}
+
+impl Default for StdoutSink {
+    fn default() -> Self {
+        return Self::new()
+    }
+}
+
+impl Sink for StdoutSink {
+    fn write_str(&mut self, str: &str) {
+        self.writer.write_all(str.as_bytes()).expect("Could not write output");
+        if str.contains('\n') {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        self.writer.flush().expect("Could not write output");
+    }
+
+    fn describe(&self) -> String {
+        return "stdout".to_string()
+    }
+}
+
+/// Writes to a temporary file next to `path`, which replaces it on `finish`.
+/// Dropping the sink unfinished removes it, so failures leave the previous output alone.
+pub struct FileSink {
+    /// `None` once finished.
+    writer: Option<BufWriter<File>>,
+    path: String,
+    temporary_path: String,
+}
+
+impl FileSink {
+    pub fn create(path: &str) -> io::Result<FileSink> {
+        let temporary_path = format!("{path}.h4.tmp");
+        return Ok(FileSink {
+            writer: Some(BufWriter::new(File::create(&temporary_path)?)),
+            path: path.to_string(),
+            temporary_path,
+        })
+    }
+
+    fn writer(&mut self) -> &mut BufWriter<File> {
+        return self.writer.as_mut().expect("The output was already finished")
+    }
+}
+
+impl Sink for FileSink {
+    fn write_str(&mut self, str: &str) {
+        self.writer().write_all(str.as_bytes()).expect("Could not write output");
+    }
+
+    fn flush(&mut self) {
+        self.writer().flush().expect("Could not write output");
+    }
+
+    fn finish(&mut self) {
+        let writer = self.writer.take().expect("The output was already finished");
+        writer.into_inner().expect("Could not write output");
+        fs::rename(&self.temporary_path, &self.path)
+            .unwrap_or_else(|e| panic!("Could not write {}: {e}", self.path));
+    }
+
+    fn describe(&self) -> String {
+        return format!("the file {}", self.path)
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        if self.writer.take().is_some() {
+            _ = fs::remove_file(&self.temporary_path);
+        }
+    }
+}
+
+/// Keeps the output in memory, readable through the shared `buffer`.
+#[derive(Clone, Default)]
+pub struct BufferSink {
+    pub buffer: Rc<RefCell<String>>,
+}
+
+impl BufferSink {
+    pub fn new() -> BufferSink {
+        return BufferSink::default()
+    }
+
+    pub fn contents(&self) -> String {
+        return self.buffer.borrow().clone()
+    }
+}
+
+impl Sink for BufferSink {
+    fn write_str(&mut self, str: &str) {
+        self.buffer.borrow_mut().push_str(str);
+    }
+
+    fn describe(&self) -> String {
+        return "a buffer".to_string()
+    }
+}
+
+/// Passes every chunk of output to a function.
+pub struct CallbackSink<'a> {
+    callback: Box<dyn FnMut(&str) + 'a>,
+}
+
+impl<'a> CallbackSink<'a> {
+    pub fn new<F: FnMut(&str) + 'a>(callback: F) -> CallbackSink<'a> {
+        return CallbackSink { callback: Box::new(callback) }
+    }
+}
+
+impl Sink for CallbackSink<'_> {
+    fn write_str(&mut self, str: &str) {
+        (self.callback)(str);
+    }
+
+    fn describe(&self) -> String {
+        return "a callback".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_sink() {
+        let path = std::env::temp_dir().join(format!("h4-sink-{}.txt", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        fs::write(&path, "old").unwrap();
+
+        let mut sink = FileSink::create(&path).unwrap();
+        sink.write_str("partial");
+        drop(sink);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert!(!std::path::Path::new(&format!("{path}.h4.tmp")).exists());
+
+        let mut sink = FileSink::create(&path).unwrap();
+        sink.write_str("new");
+        sink.finish();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        fs::remove_file(&path).unwrap();
+    }
+}